@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+use zbus::address::transport::{TcpTransportFamily, Transport, UnixSocket};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Transport '{0}' is not supported, only 'unix' and 'tcp' addresses are allowed")]
+    UnsupportedTransport(String),
+    #[error("Key '{key}' is not supported for the '{transport}' address")]
+    UnsupportedKey { transport: String, key: String },
+    #[error("Key '{0}' is set more than once")]
+    DuplicateKey(String),
+    #[error("Socket path '{0}' must be absolute and must not contain '..' segments")]
+    InvalidPath(PathBuf),
+    #[error("Only the socket path or the abstract name can end with '*'")]
+    InvalidWildcard,
+    #[error(transparent)]
+    ZBus(#[from] zbus::Error),
+}
+
+/// DBus address requested by the client, the socket path is normalized.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DBusAddress {
+    endpoint: Endpoint,
+    guid: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Endpoint {
+    Path(OsString),
+    Abstract(OsString),
+    Tcp {
+        host: String,
+        port: u16,
+        family: Option<TcpTransportFamily>,
+    },
+}
+
+impl FromStr for DBusAddress {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let (transport, options) = address.split_once(':').unwrap_or((address, ""));
+        let keys: &[&str] = match transport {
+            "unix" => &["path", "abstract", "guid"],
+            "tcp" => &["host", "port", "family", "guid"],
+            _ => return Err(Error::UnsupportedTransport(transport.into())),
+        };
+        // zbus ignores the unknown keys and uses the last value of the repeated key
+        let mut seen = HashSet::new();
+        for key in options
+            .split(',')
+            .filter_map(|option| option.split_once('='))
+        {
+            let key = key.0;
+            if !keys.contains(&key) {
+                return Err(Error::UnsupportedKey {
+                    transport: transport.into(),
+                    key: key.into(),
+                });
+            }
+            if !seen.insert(key) {
+                return Err(Error::DuplicateKey(key.into()));
+            }
+        }
+        let address = zbus::Address::from_str(address)?;
+        let endpoint = match address.transport() {
+            Transport::Unix(unix) => match unix.path() {
+                UnixSocket::File(path) => Endpoint::Path(normalize(path)?),
+                #[cfg(target_os = "linux")]
+                UnixSocket::Abstract(name) => Endpoint::Abstract(name.clone()),
+                socket => {
+                    let message = format!("Socket '{socket}' is not supported");
+                    return Err(zbus::Error::Address(message).into());
+                }
+            },
+            Transport::Tcp(tcp) => Endpoint::Tcp {
+                host: tcp.host().into(),
+                port: tcp.port(),
+                family: tcp.family(),
+            },
+            _ => return Err(Error::UnsupportedTransport(transport.into())),
+        };
+        Ok(Self {
+            endpoint,
+            guid: address.guid().map(ToString::to_string),
+        })
+    }
+}
+
+/// DBus address the clients are allowed to connect to.
+/// The trailing '*' of the socket path or the abstract name matches any suffix.
+#[derive(Debug, Clone)]
+pub struct AllowedAddress {
+    address: DBusAddress,
+    wildcard: bool,
+}
+
+impl AllowedAddress {
+    pub fn matches(&self, address: &DBusAddress) -> bool {
+        if self.address.guid.is_some() && self.address.guid != address.guid {
+            return false;
+        }
+        match (&self.address.endpoint, &address.endpoint) {
+            (Endpoint::Path(prefix), Endpoint::Path(path))
+            | (Endpoint::Abstract(prefix), Endpoint::Abstract(path))
+                if self.wildcard =>
+            {
+                path.as_bytes().starts_with(prefix.as_bytes())
+            }
+            (allowed, endpoint) => allowed == endpoint,
+        }
+    }
+}
+
+impl FromStr for AllowedAddress {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let Some(prefix) = address.strip_suffix('*') else {
+            return Ok(Self {
+                address: address.parse()?,
+                wildcard: false,
+            });
+        };
+        let last = prefix.rsplit([':', ',']).next().unwrap_or_default();
+        if !last.starts_with("path=") && !last.starts_with("abstract=") {
+            return Err(Error::InvalidWildcard);
+        }
+        let mut address: DBusAddress = prefix.parse()?;
+        // The path prefix keeps the trailing separator, so only the directory entries match
+        if let Endpoint::Path(path) = &mut address.endpoint {
+            if prefix.ends_with('/') {
+                path.push("/");
+            }
+        }
+        Ok(Self {
+            address,
+            wildcard: true,
+        })
+    }
+}
+
+// The path cannot escape the allowed directory, since '..' segments are rejected
+fn normalize(path: &Path) -> Result<OsString, Error> {
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(Error::InvalidPath(path.into()));
+    }
+    Ok(path.components().collect::<PathBuf>().into_os_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_addresses_are_rejected() {
+        for address in [
+            "unix:path=/run/dbus/../bus",
+            "unix:path=bus",
+            "unix:path=/run/bus,path=/run/dbus/system_bus_socket",
+            "unix:path=/run/bus,noncefile=/tmp/nonce",
+            "unix:dir=/tmp",
+            "unixexec:path=/bin/sh",
+            "nonce-tcp:host=127.0.0.1,port=4000,noncefile=/tmp/nonce",
+        ] {
+            assert!(DBusAddress::from_str(address).is_err(), "{address}");
+        }
+        assert!(AllowedAddress::from_str("tcp:host=127.0.0.1,port=4000*").is_err());
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(
+            DBusAddress::from_str("unix:path=/run//user/./1000/bus").unwrap(),
+            DBusAddress::from_str("unix:path=/run/user/1000/bus").unwrap()
+        );
+    }
+}
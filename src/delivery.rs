@@ -0,0 +1,502 @@
+use crate::error::Error;
+use crate::filter;
+use crate::interfaces;
+use crate::message::{DeliveryOptions, OutputMessage};
+use crate::state::SubscriptionStream;
+use crate::value::{
+    Array, ContainerType, ContainerValue, Dict, PrimitiveType, PrimitiveValue, Value,
+};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+use tokio_stream::{Stream, StreamExt};
+
+const PROPERTIES_CHANGED: &str = "PropertiesChanged";
+
+/// Maximum number of the signals delivered at the end of the window,
+/// the oldest ones are dropped and reported with `SignalsDropped`.
+const MAX_DELAYED: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+enum Window {
+    // The first message is delivered immediately, the next ones at the end of the window
+    Throttle(Duration),
+    // The messages are delivered when there are no new messages during the window
+    Debounce(Duration),
+}
+
+/// Applies the delivery options to the subscription stream.
+pub fn apply(
+    stream: SubscriptionStream,
+    options: DeliveryOptions,
+) -> crate::Result<SubscriptionStream> {
+    let DeliveryOptions {
+        throttle_ms,
+        debounce_ms,
+        latest_only,
+        filter,
+    } = options;
+    // The signals are filtered before they are delayed
+    let stream: SubscriptionStream = if filter.is_empty() {
+        stream
+    } else {
+        Box::pin(stream.filter(move |msg| match msg {
+            Ok(OutputMessage::Signal { args, .. }) => filter::matches(&filter, args),
+            _ => true,
+        }))
+    };
+    let window = match (throttle_ms, debounce_ms) {
+        (None, None) if latest_only => {
+            return Err(Error::UnsupportedFormat(
+                "Latest only requires the throttle or debounce window".into(),
+            ))
+        }
+        (None, None) => return Ok(stream),
+        (Some(throttle_ms), None) => Window::Throttle(Duration::from_millis(throttle_ms)),
+        (None, Some(debounce_ms)) => Window::Debounce(Duration::from_millis(debounce_ms)),
+        (Some(_), Some(_)) => {
+            return Err(Error::UnsupportedFormat(
+                "Throttle and debounce cannot be used together".into(),
+            ))
+        }
+    };
+    Ok(Box::pin(LimitedStream {
+        stream,
+        window,
+        latest_only,
+        delayed: VecDeque::new(),
+        dropped: 0,
+        ready: VecDeque::new(),
+        deadline: None,
+        ended: false,
+    }))
+}
+
+struct LimitedStream {
+    stream: SubscriptionStream,
+    window: Window,
+    latest_only: bool,
+    delayed: VecDeque<OutputMessage>,
+    dropped: u64,
+    ready: VecDeque<crate::Result<OutputMessage>>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    ended: bool,
+}
+
+impl LimitedStream {
+    fn push(&mut self, msg: OutputMessage) {
+        match self.window {
+            Window::Throttle(duration) if self.deadline.is_none() => {
+                self.ready.push_back(Ok(msg));
+                self.deadline = Some(Box::pin(tokio::time::sleep(duration)));
+            }
+            Window::Throttle(_) => self.delay(msg),
+            Window::Debounce(duration) => {
+                self.delay(msg);
+                match &mut self.deadline {
+                    Some(deadline) => deadline.as_mut().reset(Instant::now() + duration),
+                    None => self.deadline = Some(Box::pin(tokio::time::sleep(duration))),
+                }
+            }
+        }
+    }
+
+    fn delay(&mut self, mut msg: OutputMessage) {
+        if self.latest_only {
+            if let Some(index) = self
+                .delayed
+                .iter()
+                .position(|older| same_signal(older, &msg))
+            {
+                let older = self.delayed.remove(index);
+                if let (
+                    Some(OutputMessage::Signal { args: older, .. }),
+                    OutputMessage::Signal { args, .. },
+                ) = (older, &mut msg)
+                {
+                    merge_properties_changed(older, args);
+                }
+            }
+        }
+        if self.delayed.len() == MAX_DELAYED {
+            self.delayed.pop_front();
+            self.dropped += 1;
+        }
+        self.delayed.push_back(msg);
+    }
+
+    // The throttle window is extended while there are delayed messages
+    fn flush(&mut self) {
+        self.deadline = None;
+        if self.delayed.is_empty() {
+            return;
+        }
+        // The notice is delivered before the signals that are kept
+        if self.dropped > 0 {
+            let count = std::mem::take(&mut self.dropped);
+            self.ready
+                .push_back(Ok(OutputMessage::SignalsDropped { count }));
+        }
+        self.ready.extend(self.delayed.drain(..).map(Ok));
+        if let (Window::Throttle(duration), false) = (self.window, self.ended) {
+            self.deadline = Some(Box::pin(tokio::time::sleep(duration)));
+        }
+    }
+}
+
+impl Stream for LimitedStream {
+    type Item = crate::Result<OutputMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(item) = self.ready.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+            if self.ended {
+                return Poll::Ready(None);
+            }
+            match self.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(msg))) => {
+                    self.push(msg);
+                    continue;
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    self.ended = true;
+                    self.flush();
+                    continue;
+                }
+                Poll::Pending => {}
+            }
+            let elapsed = match &mut self.deadline {
+                Some(deadline) => deadline.as_mut().poll(cx).is_ready(),
+                None => false,
+            };
+            if !elapsed {
+                return Poll::Pending;
+            }
+            self.flush();
+        }
+    }
+}
+
+// The interface argument tells apart the `PropertiesChanged` signals of the same object
+fn same_signal(first: &OutputMessage, second: &OutputMessage) -> bool {
+    match (first, second) {
+        (
+            OutputMessage::Signal {
+                path,
+                interface,
+                name,
+                args,
+                ..
+            },
+            OutputMessage::Signal {
+                path: other_path,
+                interface: other_interface,
+                name: other_name,
+                args: other_args,
+                ..
+            },
+        ) => {
+            path == other_path
+                && interface == other_interface
+                && name == other_name
+                && (!is_properties_changed(interface, name)
+                    || properties_interface(args) == properties_interface(other_args))
+        }
+        _ => false,
+    }
+}
+
+fn is_properties_changed(interface: &str, name: &str) -> bool {
+    interface == interfaces::PROPERTIES && name == PROPERTIES_CHANGED
+}
+
+fn properties_interface(args: &[Value]) -> Option<&str> {
+    match args.first() {
+        Some(Value::Primitive(PrimitiveValue::String(interface))) => Some(interface),
+        _ => None,
+    }
+}
+
+/// The changed and invalidated properties of the older `PropertiesChanged` signal are merged
+/// into the latest one, so the coalesced signal does not lose the properties.
+fn merge_properties_changed(older: Vec<Value>, latest: &mut Vec<Value>) {
+    if !has_properties_changed_args(&older) || !has_properties_changed_args(latest) {
+        return;
+    }
+    let mut older = older.into_iter().skip(1);
+    let mut changed = changed_properties(older.next());
+    let older_invalidated = invalidated_properties(older.next());
+    let latest_invalidated = invalidated_properties(latest.pop());
+    let latest_changed = changed_properties(latest.pop());
+    changed.retain(|name, _| !latest_invalidated.contains(name));
+    let mut invalidated: Vec<String> = older_invalidated
+        .into_iter()
+        .filter(|name| !latest_changed.contains_key(name) && !latest_invalidated.contains(name))
+        .collect();
+    invalidated.extend(latest_invalidated);
+    changed.extend(latest_changed);
+    let changed = if changed.is_empty() {
+        Dict::ValueType {
+            key_type: Box::new(PrimitiveType::String),
+            value_type: Box::new(ContainerType::Variant.into()),
+        }
+    } else {
+        Dict::Value { value: changed }
+    };
+    let invalidated = if invalidated.is_empty() {
+        Array::ValueType(PrimitiveType::String.into())
+    } else {
+        Array::Value(
+            invalidated
+                .into_iter()
+                .map(|name| PrimitiveValue::String(name).into())
+                .collect(),
+        )
+    };
+    latest.push(ContainerValue::Dict(changed).into());
+    latest.push(ContainerValue::Array(invalidated).into());
+}
+
+// The arguments of `PropertiesChanged` have the signature `sa{sv}as`
+fn has_properties_changed_args(args: &[Value]) -> bool {
+    matches!(
+        args,
+        [
+            Value::Primitive(PrimitiveValue::String(_)),
+            Value::Container(ContainerValue::Dict(_)),
+            Value::Container(ContainerValue::Array(_)),
+        ]
+    )
+}
+
+fn changed_properties(value: Option<Value>) -> HashMap<String, Value> {
+    match value {
+        Some(Value::Container(ContainerValue::Dict(Dict::Value { value }))) => value,
+        _ => HashMap::new(),
+    }
+}
+
+fn invalidated_properties(value: Option<Value>) -> Vec<String> {
+    match value {
+        Some(Value::Container(ContainerValue::Array(Array::Value(values)))) => values
+            .into_iter()
+            .filter_map(|value| match value {
+                Value::Primitive(PrimitiveValue::String(name)) => Some(name),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::PrimitiveValue;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    fn signal(name: &str, value: i32) -> crate::Result<OutputMessage> {
+        Ok(OutputMessage::Signal {
+            subscriptions: Vec::new(),
+            path: "/org/example".try_into().unwrap(),
+            interface: "org.example.Test".try_into().unwrap(),
+            name: name.try_into().unwrap(),
+            header: None,
+            args: vec![PrimitiveValue::I32(value).into()],
+        })
+    }
+
+    fn properties_changed(
+        interface: &str,
+        changed: &[(&str, i32)],
+        invalidated: &[&str],
+    ) -> crate::Result<OutputMessage> {
+        let changed = changed
+            .iter()
+            .map(|(name, value)| {
+                let value = ContainerValue::Variant {
+                    value: Box::new(PrimitiveValue::I32(*value).into()),
+                };
+                (name.to_string(), value.into())
+            })
+            .collect();
+        let invalidated = invalidated
+            .iter()
+            .map(|name| PrimitiveValue::String(name.to_string()).into())
+            .collect();
+        Ok(OutputMessage::Signal {
+            subscriptions: Vec::new(),
+            path: "/org/example".try_into().unwrap(),
+            interface: interfaces::PROPERTIES.try_into().unwrap(),
+            name: PROPERTIES_CHANGED.try_into().unwrap(),
+            header: None,
+            args: vec![
+                PrimitiveValue::String(interface.into()).into(),
+                ContainerValue::Dict(Dict::Value { value: changed }).into(),
+                ContainerValue::Array(Array::Value(invalidated)).into(),
+            ],
+        })
+    }
+
+    // Messages that are ready without waiting
+    fn received(stream: &mut SubscriptionStream) -> Vec<String> {
+        let mut received = Vec::new();
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        while let Poll::Ready(Some(Ok(OutputMessage::Signal { name, args, .. }))) =
+            stream.as_mut().poll_next(&mut cx)
+        {
+            let value = serde_json::to_value(&args[0]).unwrap();
+            received.push(format!("{}{}", name, value["value"]));
+        }
+        received
+    }
+
+    fn limited(
+        options: DeliveryOptions,
+    ) -> (
+        mpsc::UnboundedSender<crate::Result<OutputMessage>>,
+        SubscriptionStream,
+    ) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stream = apply(Box::pin(UnboundedReceiverStream::new(receiver)), options).unwrap();
+        (sender, stream)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_delivers_leading_and_trailing() {
+        let (sender, mut stream) = limited(DeliveryOptions {
+            throttle_ms: Some(100),
+            ..Default::default()
+        });
+        for value in 0..3 {
+            sender.send(signal("Tick", value)).unwrap();
+        }
+        assert_eq!(received(&mut stream), vec!["Tick0"]);
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(received(&mut stream), vec!["Tick1", "Tick2"]);
+        sender.send(signal("Tick", 3)).unwrap();
+        assert!(received(&mut stream).is_empty());
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(received(&mut stream), vec!["Tick3"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_delivers_latest_after_quiet_window() {
+        let (sender, mut stream) = limited(DeliveryOptions {
+            debounce_ms: Some(100),
+            latest_only: true,
+            ..Default::default()
+        });
+        for value in 0..3 {
+            sender.send(signal("Tick", value)).unwrap();
+            sender.send(signal("Tock", value)).unwrap();
+            assert!(received(&mut stream).is_empty());
+            tokio::time::advance(Duration::from_millis(50)).await;
+        }
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert_eq!(received(&mut stream), vec!["Tick2", "Tock2"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latest_only_merges_properties_of_same_interface() {
+        let (sender, mut stream) = limited(DeliveryOptions {
+            debounce_ms: Some(100),
+            latest_only: true,
+            ..Default::default()
+        });
+        for signal in [
+            properties_changed("org.example.A", &[("X", 0), ("Y", 0)], &["Z"]),
+            properties_changed("org.example.B", &[("X", 1)], &[]),
+            properties_changed("org.example.A", &[("X", 2), ("Z", 2)], &["Y"]),
+        ] {
+            sender.send(signal).unwrap();
+        }
+        assert!(received(&mut stream).is_empty());
+        tokio::time::advance(Duration::from_millis(100)).await;
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        let mut received = Vec::new();
+        while let Poll::Ready(Some(Ok(msg))) = stream.as_mut().poll_next(&mut cx) {
+            received.push(serde_json::to_value(msg).unwrap()["Signal"]["args"].clone());
+        }
+        assert_eq!(
+            received,
+            vec![
+                serde_json::json!([
+                    {"type": "string", "value": "org.example.B"},
+                    {"type": "dict", "value": {"X": {"type": "variant", "value": {"type": "i32", "value": 1}}}},
+                    {"type": "array", "value": []},
+                ]),
+                serde_json::json!([
+                    {"type": "string", "value": "org.example.A"},
+                    {"type": "dict", "value": {
+                        "X": {"type": "variant", "value": {"type": "i32", "value": 2}},
+                        "Z": {"type": "variant", "value": {"type": "i32", "value": 2}},
+                    }},
+                    {"type": "array", "value": [{"type": "string", "value": "Y"}]},
+                ]),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_delayed_signals_are_reported() {
+        let (sender, mut stream) = limited(DeliveryOptions {
+            throttle_ms: Some(100),
+            ..Default::default()
+        });
+        for value in 0..100 {
+            sender.send(signal("Tick", value)).unwrap();
+        }
+        assert_eq!(received(&mut stream), vec!["Tick0"]);
+        tokio::time::advance(Duration::from_millis(100)).await;
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        let dropped = (99 - MAX_DELAYED) as u64;
+        assert!(matches!(
+            stream.as_mut().poll_next(&mut cx),
+            Poll::Ready(Some(Ok(OutputMessage::SignalsDropped { count }))) if count == dropped
+        ));
+        assert_eq!(received(&mut stream).last().unwrap(), "Tick99");
+    }
+
+    #[test]
+    fn throttle_and_debounce_are_exclusive() {
+        let options = DeliveryOptions {
+            throttle_ms: Some(100),
+            debounce_ms: Some(100),
+            ..Default::default()
+        };
+        assert!(matches!(
+            apply(Box::pin(tokio_stream::empty()), options),
+            Err(Error::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn latest_only_requires_window() {
+        let options = DeliveryOptions {
+            latest_only: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            apply(Box::pin(tokio_stream::empty()), options),
+            Err(Error::UnsupportedFormat(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_without_window_is_unchanged() {
+        let mut stream = apply(
+            Box::pin(tokio_stream::iter(vec![signal("Tick", 0)])),
+            DeliveryOptions::default(),
+        )
+        .unwrap();
+        assert!(stream.next().await.is_some());
+    }
+}
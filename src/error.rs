@@ -1,10 +1,15 @@
 use crate::message::{OutputMessage, RequestId};
-use crate::value;
+use crate::value::Value;
+use crate::{export, introspection, value};
 use serde::Serialize;
 use std::fmt::{Display, Formatter};
+use std::time::Duration;
 use thiserror::Error;
+use tracing::warn;
+use zbus::DBusError;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("DBus error: {0}")]
     DBusError(#[from] zbus::Error),
@@ -18,6 +23,20 @@ pub enum Error {
     DBusFormatError(#[from] zvariant::Error),
     #[error("DBus value error: {0}")]
     DBusValueError(#[from] value::Error),
+    #[error("DBus introspection error: {0}")]
+    IntrospectionError(#[from] introspection::Error),
+    #[error("Exported object error: {0}")]
+    ExportError(#[from] export::Error),
+    #[error("Request task error: {0}")]
+    TaskError(#[from] tokio::task::JoinError),
+    #[error("Too many requests in flight, the limit is {0}")]
+    TooManyRequests(usize),
+    #[error("Request timed out after {} ms", .0.as_millis())]
+    Timeout(Duration),
+    #[error("Request is cancelled")]
+    Cancelled,
+    #[error("{0} is not available on the shared connection")]
+    SharedConnection(&'static str),
 }
 
 impl Error {
@@ -29,6 +48,13 @@ impl Error {
             Error::JsonError(_) => ErrorType::JsonError,
             Error::DBusFormatError(_) => ErrorType::DBusFormatError,
             Error::DBusValueError(_) => ErrorType::DBusValueError,
+            Error::IntrospectionError(_) => ErrorType::IntrospectionError,
+            Error::ExportError(_) => ErrorType::ExportError,
+            Error::TaskError(_) => ErrorType::ServerError,
+            Error::TooManyRequests(_) => ErrorType::TooManyRequests,
+            Error::Timeout(_) => ErrorType::Timeout,
+            Error::Cancelled => ErrorType::Cancelled,
+            Error::SharedConnection(_) => ErrorType::SharedConnection,
         }
     }
 }
@@ -78,6 +104,13 @@ impl From<Error> for OutputMessage {
 
 impl From<RequestError> for OutputMessage {
     fn from(RequestError { request_id, error }: RequestError) -> Self {
+        let error = match error {
+            Error::DBusError(error) => match method_error(request_id, error) {
+                Ok(method_error) => return method_error,
+                Err(error) => Error::DBusError(error),
+            },
+            error => error,
+        };
         OutputMessage::Error {
             request_id,
             error_type: error.error_type(),
@@ -86,6 +119,43 @@ impl From<RequestError> for OutputMessage {
     }
 }
 
+// Error replies are converted to MethodError regardless of the zbus API that received them
+fn method_error(
+    request_id: Option<RequestId>,
+    error: zbus::Error,
+) -> Result<OutputMessage, zbus::Error> {
+    match error {
+        zbus::Error::MethodError(error_name, message, msg) => {
+            let args = Value::try_to_array_from_body(&msg.body()).unwrap_or_else(|err| {
+                warn!("Error reply arguments cannot be decoded: {}", err);
+                Vec::new()
+            });
+            Ok(OutputMessage::MethodError {
+                request_id,
+                error_name: error_name.to_string(),
+                message,
+                args,
+            })
+        }
+        zbus::Error::FDO(error) => match *error {
+            zbus::fdo::Error::ZBus(error) => method_error(request_id, error),
+            error => {
+                let message = error.description().map(ToString::to_string);
+                Ok(OutputMessage::MethodError {
+                    request_id,
+                    error_name: error.name().to_string(),
+                    args: message
+                        .iter()
+                        .map(|message| zvariant::Value::from(message.as_str()).into())
+                        .collect(),
+                    message,
+                })
+            }
+        },
+        error => Err(error),
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub enum ErrorType {
     DBusError,
@@ -94,4 +164,63 @@ pub enum ErrorType {
     JsonError,
     DBusFormatError,
     DBusValueError,
+    IntrospectionError,
+    ExportError,
+    TooManyRequests,
+    Timeout,
+    Cancelled,
+    SharedConnection,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(error: zbus::Error) -> serde_json::Value {
+        let msg: OutputMessage = RequestError::new(Some(1), error).into();
+        serde_json::to_value(msg).unwrap()
+    }
+
+    #[test]
+    fn error_reply_to_method_error() {
+        let call = zbus::Message::method_call("/org/example", "Fail")
+            .unwrap()
+            .build(&())
+            .unwrap();
+        let reply = zbus::Message::error(&call.header(), "org.example.Error.Failed")
+            .unwrap()
+            .build(&("Something failed", 42u32))
+            .unwrap();
+        assert_eq!(
+            output(reply.into()),
+            serde_json::json!({"MethodError": {
+                "requestId": 1,
+                "errorName": "org.example.Error.Failed",
+                "message": "Something failed",
+                "args": [{"type": "string", "value": "Something failed"}, {"type": "u32", "value": 42}]
+            }})
+        );
+    }
+
+    #[test]
+    fn fdo_error_to_method_error() {
+        let error = zbus::fdo::Error::ServiceUnknown("Unknown service".into());
+        assert_eq!(
+            output(error.into()),
+            serde_json::json!({"MethodError": {
+                "requestId": 1,
+                "errorName": "org.freedesktop.DBus.Error.ServiceUnknown",
+                "message": "Unknown service",
+                "args": [{"type": "string", "value": "Unknown service"}]
+            }})
+        );
+    }
+
+    #[test]
+    fn other_errors_are_not_method_errors() {
+        assert_eq!(
+            output(zbus::Error::InvalidField)["Error"]["errorType"],
+            "DBusError"
+        );
+    }
 }
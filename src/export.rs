@@ -0,0 +1,1004 @@
+use crate::interfaces;
+use crate::introspection::annotations;
+use crate::message::{CallId, OutputMessage};
+use crate::state::SubscriptionStream;
+use crate::value::{self, BodySignature, Value};
+use futures_util::StreamExt;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::{instrument, trace};
+use zbus::fdo;
+use zbus::message::{Flags, Type};
+use zbus::names::{
+    InterfaceName, MemberName, OwnedErrorName, OwnedInterfaceName, OwnedMemberName,
+    OwnedPropertyName,
+};
+use zbus::{MatchRule, MessageStream};
+use zbus_xml::{ArgDirection, Node, PropertyAccess};
+use zvariant::{ObjectPath, OwnedObjectPath};
+
+// The client that doesn't reply to the incoming calls cannot grow the table without limit
+const MAX_PENDING_CALLS: usize = 256;
+
+const DOCTYPE: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN" "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">"#;
+
+// The standard interfaces implemented by the proxy for every exported object
+const STANDARD_INTERFACES: &str = r#"  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml_data" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <method name="Set">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="in"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface_name" type="s"/>
+      <arg name="changed_properties" type="a{sv}"/>
+      <arg name="invalidated_properties" type="as"/>
+    </signal>
+  </interface>
+"#;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Interface definition cannot be parsed: {0}")]
+    InvalidXml(#[from] zbus_xml::Error),
+    #[error("The object '{0}' is not exported")]
+    ObjectNotExported(OwnedObjectPath),
+    #[error("The exported object '{path}' doesn't implement interface '{interface}'")]
+    InterfaceNotExported {
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+    },
+    #[error("The interface '{interface}' of the exported object '{path}' doesn't have property '{property}'")]
+    PropertyNotFound {
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+        property: OwnedPropertyName,
+    },
+    #[error("Incoming method call {0} is not pending")]
+    CallNotFound(CallId),
+}
+
+/// Interface of the exported object, the introspection XML or the JSON description.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InterfaceDefinition {
+    Xml(String),
+    Json(ExportedInterface),
+}
+
+/// Same as the interface description of the `Introspection` message, the value types are not required.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedInterface {
+    name: OwnedInterfaceName,
+    #[serde(default)]
+    methods: Vec<ExportedMethod>,
+    #[serde(default)]
+    signals: Vec<ExportedSignal>,
+    #[serde(default)]
+    properties: Vec<ExportedProperty>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedMethod {
+    name: OwnedMemberName,
+    #[serde(default)]
+    in_args: Vec<ExportedArg>,
+    #[serde(default)]
+    out_args: Vec<ExportedArg>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedSignal {
+    name: OwnedMemberName,
+    #[serde(default)]
+    args: Vec<ExportedArg>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedProperty {
+    name: OwnedPropertyName,
+    #[serde(deserialize_with = "deserialize_signature")]
+    signature: zvariant::Signature,
+    access: PropertyAccess,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedArg {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(deserialize_with = "deserialize_signature")]
+    signature: zvariant::Signature,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+// The signature is deserialized from the owned string, the buffered JSON values cannot be borrowed
+fn deserialize_signature<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<zvariant::Signature, D::Error> {
+    let signature = String::deserialize(deserializer)?;
+    zvariant::Signature::from_str(&signature).map_err(serde::de::Error::custom)
+}
+
+/// Initial property values by name for each interface name.
+pub type ExportedProperties =
+    HashMap<OwnedInterfaceName, HashMap<OwnedPropertyName, serde_json::Value>>;
+
+#[derive(Debug)]
+struct ExportedObject {
+    interfaces: Vec<ExportedInterface>,
+    values: HashMap<(OwnedInterfaceName, OwnedPropertyName), zvariant::Value<'static>>,
+}
+
+/// Objects exported by the client on the connection of the WebSocket.
+///
+/// The method calls of the exported interfaces are forwarded to the client, the standard interfaces
+/// are implemented by the proxy. The property values are stored by the proxy.
+#[derive(Debug, Default)]
+pub struct ExportedObjects {
+    objects: Mutex<HashMap<OwnedObjectPath, ExportedObject>>,
+    calls: Mutex<PendingCalls>,
+    serving: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct PendingCalls {
+    last_id: CallId,
+    calls: HashMap<CallId, PendingCall>,
+}
+
+// The incoming method call waiting for the client reply
+#[derive(Debug)]
+struct PendingCall {
+    msg: zbus::Message,
+    signature: BodySignature,
+}
+
+// Reply of the proxy to the incoming method call
+enum Reply {
+    Introspection(String),
+    Property(zvariant::Value<'static>),
+    Properties(HashMap<String, zvariant::Value<'static>>),
+    PropertySet(OutputMessage),
+    Forward(OutputMessage),
+}
+
+impl ExportedObjects {
+    /// Exports the object, the previously exported object with the same path is replaced.
+    pub fn export(
+        &self,
+        path: OwnedObjectPath,
+        interfaces: Vec<InterfaceDefinition>,
+        properties: ExportedProperties,
+    ) -> crate::Result<()> {
+        let mut object = ExportedObject {
+            interfaces: Vec::new(),
+            values: HashMap::new(),
+        };
+        for definition in interfaces {
+            object.interfaces.extend(definition.parse()?);
+        }
+        for (interface, values) in properties {
+            for (name, value) in values {
+                let property = object.property(&path, &interface, &name)?;
+                let value = value::try_value_from_json(value, &property.signature)?;
+                object.values.insert((interface.clone(), name), value);
+            }
+        }
+        self.objects.lock().unwrap().insert(path, object);
+        Ok(())
+    }
+
+    /// Returns false if the object is not exported.
+    /// The pending incoming calls of the object are failed, the client cannot reply to them anymore.
+    pub async fn unexport(&self, connection: &zbus::Connection, path: &OwnedObjectPath) -> bool {
+        if self.objects.lock().unwrap().remove(path).is_none() {
+            return false;
+        }
+        let calls: Vec<_> = {
+            let mut calls = self.calls.lock().unwrap();
+            let ids: Vec<_> = calls
+                .calls
+                .iter()
+                .filter(|(_, call)| call.msg.header().path().is_some_and(|p| *p == **path))
+                .map(|(call_id, _)| *call_id)
+                .collect();
+            ids.iter()
+                .filter_map(|call_id| calls.calls.remove(call_id))
+                .collect()
+        };
+        fail_calls(connection, calls, "The object is unexported").await;
+        true
+    }
+
+    /// Fails all pending incoming calls, so the callers don't wait for the timeout.
+    pub async fn close(&self, connection: &zbus::Connection) {
+        let calls: Vec<_> = {
+            let mut calls = self.calls.lock().unwrap();
+            calls.calls.drain().map(|(_, call)| call).collect()
+        };
+        fail_calls(connection, calls, "The WebSocket is closed").await;
+    }
+
+    /// Sets the property value, returns the value to emit the `PropertiesChanged` signal.
+    pub fn set_property(
+        &self,
+        path: &OwnedObjectPath,
+        interface: &OwnedInterfaceName,
+        name: &OwnedPropertyName,
+        value: serde_json::Value,
+    ) -> crate::Result<zvariant::Value<'static>> {
+        let mut objects = self.objects.lock().unwrap();
+        let object = objects
+            .get_mut(path)
+            .ok_or_else(|| Error::ObjectNotExported(path.clone()))?;
+        let property = object.property(path, interface, name)?;
+        let value = value::try_value_from_json(value, &property.signature)?;
+        object
+            .values
+            .insert((interface.clone(), name.clone()), value.try_clone()?);
+        Ok(value)
+    }
+
+    /// Replies to the incoming method call with the output arguments of the method.
+    pub async fn return_call(
+        &self,
+        connection: &zbus::Connection,
+        call_id: CallId,
+        args: Vec<serde_json::Value>,
+    ) -> crate::Result<()> {
+        let signature = self
+            .calls
+            .lock()
+            .unwrap()
+            .calls
+            .get(&call_id)
+            .map(|call| call.signature.clone())
+            .ok_or(Error::CallNotFound(call_id))?;
+        // The call is pending until the arguments are converted, so the client can fix them
+        let body = value::try_structure_from_json(args, &signature)?;
+        let call = self.take_call(call_id)?;
+        match body {
+            Some(body) => connection.reply(&call.msg.header(), &body).await?,
+            None => connection.reply(&call.msg.header(), &()).await?,
+        }
+        Ok(())
+    }
+
+    /// Replies to the incoming method call with the error.
+    pub async fn return_error(
+        &self,
+        connection: &zbus::Connection,
+        call_id: CallId,
+        error_name: OwnedErrorName,
+        message: Option<String>,
+    ) -> crate::Result<()> {
+        let call = self.take_call(call_id)?;
+        let header = call.msg.header();
+        match message {
+            Some(message) => {
+                connection
+                    .reply_error(&header, &error_name, &(message,))
+                    .await?
+            }
+            None => connection.reply_error(&header, &error_name, &()).await?,
+        }
+        Ok(())
+    }
+
+    fn take_call(&self, call_id: CallId) -> Result<PendingCall, Error> {
+        self.calls
+            .lock()
+            .unwrap()
+            .calls
+            .remove(&call_id)
+            .ok_or(Error::CallNotFound(call_id))
+    }
+
+    async fn dispatch(
+        &self,
+        connection: &zbus::Connection,
+        msg: zbus::Message,
+    ) -> crate::Result<Option<OutputMessage>> {
+        let header = msg.header();
+        let output_message = match self.route(&msg) {
+            Ok(Reply::Introspection(xml)) => {
+                connection.reply(&header, &xml).await?;
+                None
+            }
+            Ok(Reply::Property(value)) => {
+                connection.reply(&header, &value).await?;
+                None
+            }
+            Ok(Reply::Properties(values)) => {
+                connection.reply(&header, &values).await?;
+                None
+            }
+            Ok(Reply::PropertySet(output_message)) => {
+                connection.reply(&header, &()).await?;
+                if let OutputMessage::ExportedPropertySet {
+                    path,
+                    interface,
+                    name,
+                    ..
+                } = &output_message
+                {
+                    let value = self.value(path, interface, name)?;
+                    properties_changed(connection, path, interface, name, &value).await?;
+                }
+                Some(output_message)
+            }
+            Ok(Reply::Forward(output_message)) => Some(output_message),
+            Err(err) => {
+                trace!("Incoming method call is rejected: {}", err);
+                connection.reply_dbus_error(&header, err).await?;
+                None
+            }
+        };
+        Ok(output_message)
+    }
+
+    fn route(&self, msg: &zbus::Message) -> Result<Reply, fdo::Error> {
+        let header = msg.header();
+        let (Some(path), Some(member)) = (header.path(), header.member()) else {
+            return Err(fdo::Error::InvalidArgs(
+                "Path and member must be set".into(),
+            ));
+        };
+        let interface = header.interface();
+        let mut objects = self.objects.lock().unwrap();
+        if interface.is_some_and(|interface| interface == interfaces::INTROSPECTABLE)
+            && member == "Introspect"
+        {
+            return introspect(&objects, path).map(Reply::Introspection);
+        }
+        let path = OwnedObjectPath::from(path.to_owned());
+        let object = objects
+            .get_mut(&path)
+            .ok_or_else(|| fdo::Error::UnknownObject(format!("Unknown object '{}'", path)))?;
+        if interface.is_some_and(|interface| interface == interfaces::PROPERTIES) {
+            return object.properties_call(&path, member, msg);
+        }
+        let (interface, method) = object.method(interface, member)?;
+        let signature: BodySignature = method
+            .in_args
+            .iter()
+            .map(|arg| arg.signature.clone())
+            .collect::<Vec<_>>()
+            .into();
+        if msg.body().signature().to_string() != signature.to_string() {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Invalid arguments signature, expected '{}'",
+                signature
+            )));
+        }
+        let args = Value::try_to_array_from_body(&msg.body())
+            .map_err(|err| fdo::Error::InvalidArgs(err.to_string()))?;
+        let signature: BodySignature = method
+            .out_args
+            .iter()
+            .map(|arg| arg.signature.clone())
+            .collect::<Vec<_>>()
+            .into();
+        let interface = interface.name.clone();
+        let method_name = method.name.clone();
+        let no_reply_expected = msg
+            .primary_header()
+            .flags()
+            .contains(Flags::NoReplyExpected);
+        let call_id = {
+            let mut calls = self.calls.lock().unwrap();
+            // The calls without reply are not pending, the client must not reply to them
+            if !no_reply_expected && calls.calls.len() >= MAX_PENDING_CALLS {
+                return Err(fdo::Error::LimitsExceeded(
+                    "Too many pending method calls".into(),
+                ));
+            }
+            calls.last_id += 1;
+            let call_id = calls.last_id;
+            if !no_reply_expected {
+                let msg = msg.clone();
+                calls.calls.insert(call_id, PendingCall { msg, signature });
+            }
+            call_id
+        };
+        Ok(Reply::Forward(OutputMessage::IncomingMethodCall {
+            call_id,
+            path,
+            interface,
+            method_name,
+            no_reply_expected,
+            header: msg.into(),
+            args,
+        }))
+    }
+
+    fn value(
+        &self,
+        path: &OwnedObjectPath,
+        interface: &OwnedInterfaceName,
+        name: &OwnedPropertyName,
+    ) -> crate::Result<zvariant::Value<'static>> {
+        let objects = self.objects.lock().unwrap();
+        let value = objects
+            .get(path)
+            .and_then(|object| object.values.get(&(interface.clone(), name.clone())))
+            .ok_or_else(|| Error::PropertyNotFound {
+                path: path.clone(),
+                interface: interface.clone(),
+                property: name.clone(),
+            })?;
+        Ok(value.try_clone()?)
+    }
+}
+
+async fn fail_calls(connection: &zbus::Connection, calls: Vec<PendingCall>, message: &str) {
+    for call in calls {
+        let error = fdo::Error::Failed(message.into());
+        if let Err(err) = connection.reply_dbus_error(&call.msg.header(), error).await {
+            trace!("Incoming method call cannot be failed: {}", err);
+        }
+    }
+}
+
+impl ExportedObject {
+    fn interface(
+        &self,
+        path: &OwnedObjectPath,
+        interface: &OwnedInterfaceName,
+    ) -> Result<&ExportedInterface, Error> {
+        self.interfaces
+            .iter()
+            .find(|i| i.name == *interface)
+            .ok_or_else(|| Error::InterfaceNotExported {
+                path: path.clone(),
+                interface: interface.clone(),
+            })
+    }
+
+    fn property(
+        &self,
+        path: &OwnedObjectPath,
+        interface: &OwnedInterfaceName,
+        name: &OwnedPropertyName,
+    ) -> Result<&ExportedProperty, Error> {
+        self.interface(path, interface)?
+            .properties
+            .iter()
+            .find(|p| p.name == *name)
+            .ok_or_else(|| Error::PropertyNotFound {
+                path: path.clone(),
+                interface: interface.clone(),
+                property: name.clone(),
+            })
+    }
+
+    // If the interface is not specified, the first interface that has the method is used
+    fn method(
+        &self,
+        interface: Option<&InterfaceName<'_>>,
+        member: &MemberName<'_>,
+    ) -> Result<(&ExportedInterface, &ExportedMethod), fdo::Error> {
+        match interface {
+            Some(name) => {
+                let interface = self
+                    .interfaces
+                    .iter()
+                    .find(|interface| interface.name == *name)
+                    .ok_or_else(|| {
+                        fdo::Error::UnknownInterface(format!("Unknown interface '{}'", name))
+                    })?;
+                let method = interface.method(member).ok_or_else(|| {
+                    fdo::Error::UnknownMethod(format!("Unknown method '{}'", member))
+                })?;
+                Ok((interface, method))
+            }
+            None => self
+                .interfaces
+                .iter()
+                .find_map(|interface| interface.method(member).map(|method| (interface, method)))
+                .ok_or_else(|| fdo::Error::UnknownMethod(format!("Unknown method '{}'", member))),
+        }
+    }
+
+    fn properties_call(
+        &mut self,
+        path: &OwnedObjectPath,
+        member: &MemberName<'_>,
+        msg: &zbus::Message,
+    ) -> Result<Reply, fdo::Error> {
+        let body = msg.body();
+        let invalid_args = |err: zbus::Error| fdo::Error::InvalidArgs(err.to_string());
+        match member.as_str() {
+            "Get" => {
+                let (interface, name): (OwnedInterfaceName, String) =
+                    body.deserialize().map_err(invalid_args)?;
+                let property = self.readable_property(&interface, &name)?;
+                let value = self
+                    .values
+                    .get(&(interface, property.name.clone()))
+                    .ok_or_else(|| {
+                        fdo::Error::Failed(format!("Property '{}' value is not set", name))
+                    })?;
+                Ok(Reply::Property(
+                    value.try_clone().map_err(zbus::Error::from)?,
+                ))
+            }
+            "GetAll" => {
+                let (interface,): (OwnedInterfaceName,) =
+                    body.deserialize().map_err(invalid_args)?;
+                let exported = self.interfaces.iter().find(|i| i.name == interface);
+                let Some(exported) = exported else {
+                    return Err(fdo::Error::UnknownInterface(format!(
+                        "Unknown interface '{}'",
+                        interface
+                    )));
+                };
+                let mut values = HashMap::new();
+                for property in exported.properties.iter().filter(|p| p.access.read()) {
+                    let key = (interface.clone(), property.name.clone());
+                    if let Some(value) = self.values.get(&key) {
+                        let value = value.try_clone().map_err(zbus::Error::from)?;
+                        values.insert(property.name.to_string(), value);
+                    }
+                }
+                Ok(Reply::Properties(values))
+            }
+            "Set" => {
+                let (interface, name, value): (OwnedInterfaceName, String, zvariant::OwnedValue) =
+                    body.deserialize().map_err(invalid_args)?;
+                let property = self.interface_property(&interface, &name)?;
+                if !property.access.write() {
+                    return Err(fdo::Error::PropertyReadOnly(format!(
+                        "Property '{}' is read-only",
+                        name
+                    )));
+                }
+                let value = zvariant::Value::from(value);
+                if value.value_signature() != &property.signature {
+                    return Err(fdo::Error::InvalidArgs(format!(
+                        "Property '{}' type is '{}'",
+                        name, property.signature
+                    )));
+                }
+                let name = property.name.clone();
+                let output_message = OutputMessage::ExportedPropertySet {
+                    path: path.clone(),
+                    interface: interface.clone(),
+                    name: name.clone(),
+                    value: (&value).into(),
+                };
+                self.values.insert((interface, name), value);
+                Ok(Reply::PropertySet(output_message))
+            }
+            _ => Err(fdo::Error::UnknownMethod(format!(
+                "Unknown method '{}'",
+                member
+            ))),
+        }
+    }
+
+    fn interface_property(
+        &self,
+        interface: &OwnedInterfaceName,
+        name: &str,
+    ) -> Result<&ExportedProperty, fdo::Error> {
+        self.interfaces
+            .iter()
+            .find(|i| i.name == *interface)
+            .ok_or_else(|| {
+                fdo::Error::UnknownInterface(format!("Unknown interface '{}'", interface))
+            })?
+            .properties
+            .iter()
+            .find(|p| p.name.as_str() == name)
+            .ok_or_else(|| fdo::Error::UnknownProperty(format!("Unknown property '{}'", name)))
+    }
+
+    fn readable_property(
+        &self,
+        interface: &OwnedInterfaceName,
+        name: &str,
+    ) -> Result<&ExportedProperty, fdo::Error> {
+        let property = self.interface_property(interface, name)?;
+        if !property.access.read() {
+            return Err(fdo::Error::AccessDenied(format!(
+                "Property '{}' is write-only",
+                name
+            )));
+        }
+        Ok(property)
+    }
+}
+
+impl InterfaceDefinition {
+    // The XML can be either the introspection document or a single interface element
+    fn parse(self) -> Result<Vec<ExportedInterface>, Error> {
+        match self {
+            InterfaceDefinition::Json(interface) => Ok(vec![interface]),
+            InterfaceDefinition::Xml(xml) => {
+                let xml = if xml.trim_start().starts_with("<interface") {
+                    format!("<node>{}</node>", xml)
+                } else {
+                    xml
+                };
+                let node = Node::from_reader(xml.as_bytes())?;
+                Ok(node.interfaces().iter().map(Into::into).collect())
+            }
+        }
+    }
+}
+
+impl From<&zbus_xml::Interface<'_>> for ExportedInterface {
+    fn from(interface: &zbus_xml::Interface<'_>) -> Self {
+        Self {
+            name: interface.name().into(),
+            methods: interface.methods().iter().map(Into::into).collect(),
+            signals: interface.signals().iter().map(Into::into).collect(),
+            properties: interface.properties().iter().map(Into::into).collect(),
+            annotations: annotations(interface.annotations()),
+        }
+    }
+}
+
+impl From<&zbus_xml::Method<'_>> for ExportedMethod {
+    fn from(method: &zbus_xml::Method<'_>) -> Self {
+        let (out_args, in_args) = method
+            .args()
+            .iter()
+            .partition::<Vec<_>, _>(|arg| arg.direction() == Some(ArgDirection::Out));
+        Self {
+            name: method.name().into(),
+            in_args: in_args.into_iter().map(Into::into).collect(),
+            out_args: out_args.into_iter().map(Into::into).collect(),
+            annotations: annotations(method.annotations()),
+        }
+    }
+}
+
+impl From<&zbus_xml::Signal<'_>> for ExportedSignal {
+    fn from(signal: &zbus_xml::Signal<'_>) -> Self {
+        Self {
+            name: signal.name().into(),
+            args: signal.args().iter().map(Into::into).collect(),
+            annotations: annotations(signal.annotations()),
+        }
+    }
+}
+
+impl From<&zbus_xml::Property<'_>> for ExportedProperty {
+    fn from(property: &zbus_xml::Property<'_>) -> Self {
+        Self {
+            name: property.name().into(),
+            signature: property.ty().inner().clone(),
+            access: property.access(),
+            annotations: annotations(property.annotations()),
+        }
+    }
+}
+
+impl From<&zbus_xml::Arg> for ExportedArg {
+    fn from(arg: &zbus_xml::Arg) -> Self {
+        Self {
+            name: arg.name().map(ToString::to_string),
+            signature: arg.ty().inner().clone(),
+            annotations: annotations(arg.annotations()),
+        }
+    }
+}
+
+/// Stream of the incoming method calls forwarded to the client, it is created with the first exported object.
+/// The other method calls are replied by the proxy.
+#[instrument(skip(objects, connection))]
+pub async fn serve(
+    objects: &Arc<ExportedObjects>,
+    connection: &zbus::Connection,
+) -> crate::Result<Option<SubscriptionStream>> {
+    if objects.serving.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+    let rule = MatchRule::builder().msg_type(Type::MethodCall).build();
+    let calls = match MessageStream::for_match_rule(rule, connection, None).await {
+        Ok(calls) => calls,
+        Err(err) => {
+            objects.serving.store(false, Ordering::SeqCst);
+            return Err(err.into());
+        }
+    };
+    let objects = objects.clone();
+    let connection = connection.clone();
+    Ok(Some(Box::pin(
+        calls
+            .then(move |msg| {
+                let objects = objects.clone();
+                let connection = connection.clone();
+                async move {
+                    match msg {
+                        Ok(msg) => objects.dispatch(&connection, msg).await,
+                        Err(err) => Err(err.into()),
+                    }
+                }
+            })
+            .filter_map(|result| async move { result.transpose() }),
+    )))
+}
+
+/// Emits the `PropertiesChanged` signal of the exported object.
+pub async fn properties_changed(
+    connection: &zbus::Connection,
+    path: &OwnedObjectPath,
+    interface: &OwnedInterfaceName,
+    name: &OwnedPropertyName,
+    value: &zvariant::Value<'_>,
+) -> crate::Result<()> {
+    let changed = HashMap::from([(name.as_str(), value)]);
+    connection
+        .emit_signal(
+            None::<()>,
+            path,
+            interfaces::PROPERTIES,
+            "PropertiesChanged",
+            &(interface.as_str(), changed, Vec::<&str>::new()),
+        )
+        .await?;
+    Ok(())
+}
+
+// The ancestors of the exported objects are introspected as the nodes without interfaces
+fn introspect(
+    objects: &HashMap<OwnedObjectPath, ExportedObject>,
+    path: &ObjectPath<'_>,
+) -> Result<String, fdo::Error> {
+    let object = objects.get(&OwnedObjectPath::from(path.to_owned()));
+    let children: BTreeSet<&str> = objects
+        .keys()
+        .filter_map(|child| child_name(path.as_str(), child.as_str()))
+        .collect();
+    if object.is_none() && children.is_empty() {
+        return Err(fdo::Error::UnknownObject(format!(
+            "Unknown object '{}'",
+            path
+        )));
+    }
+    let mut xml = format!("{}\n<node>\n", DOCTYPE);
+    if let Some(object) = object {
+        xml.push_str(STANDARD_INTERFACES);
+        for interface in &object.interfaces {
+            interface.write_xml(&mut xml);
+        }
+    }
+    for child in children {
+        let _ = writeln!(xml, r#"  <node name="{}"/>"#, escape(child));
+    }
+    xml.push_str("</node>\n");
+    Ok(xml)
+}
+
+fn child_name<'a>(parent: &str, path: &'a str) -> Option<&'a str> {
+    let relative = match parent {
+        "/" => path.strip_prefix('/')?,
+        parent => path.strip_prefix(parent)?.strip_prefix('/')?,
+    };
+    relative.split('/').next().filter(|name| !name.is_empty())
+}
+
+impl ExportedInterface {
+    fn method(&self, member: &MemberName<'_>) -> Option<&ExportedMethod> {
+        self.methods.iter().find(|method| method.name == *member)
+    }
+
+    fn write_xml(&self, xml: &mut String) {
+        let _ = writeln!(xml, r#"  <interface name="{}">"#, escape(&self.name));
+        for method in &self.methods {
+            let _ = writeln!(xml, r#"    <method name="{}">"#, escape(&method.name));
+            for arg in &method.in_args {
+                arg.write_xml(xml, Some("in"));
+            }
+            for arg in &method.out_args {
+                arg.write_xml(xml, Some("out"));
+            }
+            write_annotations(xml, &method.annotations, "      ");
+            xml.push_str("    </method>\n");
+        }
+        for signal in &self.signals {
+            let _ = writeln!(xml, r#"    <signal name="{}">"#, escape(&signal.name));
+            for arg in &signal.args {
+                arg.write_xml(xml, None);
+            }
+            write_annotations(xml, &signal.annotations, "      ");
+            xml.push_str("    </signal>\n");
+        }
+        for property in &self.properties {
+            let access = match property.access {
+                PropertyAccess::Read => "read",
+                PropertyAccess::Write => "write",
+                PropertyAccess::ReadWrite => "readwrite",
+            };
+            let _ = writeln!(
+                xml,
+                r#"    <property name="{}" type="{}" access="{}">"#,
+                escape(&property.name),
+                escape(&property.signature.to_string()),
+                access
+            );
+            write_annotations(xml, &property.annotations, "      ");
+            xml.push_str("    </property>\n");
+        }
+        write_annotations(xml, &self.annotations, "    ");
+        xml.push_str("  </interface>\n");
+    }
+}
+
+impl ExportedArg {
+    fn write_xml(&self, xml: &mut String, direction: Option<&str>) {
+        xml.push_str("      <arg");
+        if let Some(name) = &self.name {
+            let _ = write!(xml, r#" name="{}""#, escape(name));
+        }
+        let _ = write!(xml, r#" type="{}""#, escape(&self.signature.to_string()));
+        if let Some(direction) = direction {
+            let _ = write!(xml, r#" direction="{}""#, direction);
+        }
+        if self.annotations.is_empty() {
+            xml.push_str("/>\n");
+        } else {
+            xml.push_str(">\n");
+            write_annotations(xml, &self.annotations, "        ");
+            xml.push_str("      </arg>\n");
+        }
+    }
+}
+
+fn write_annotations(xml: &mut String, annotations: &BTreeMap<String, String>, indent: &str) {
+    for (name, value) in annotations {
+        let _ = writeln!(
+            xml,
+            r#"{}<annotation name="{}" value="{}"/>"#,
+            indent,
+            escape(name),
+            escape(value)
+        );
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XML: &str = r#"
+        <interface name="org.mpris.MediaPlayer2.Player">
+          <method name="Seek">
+            <arg name="Offset" type="x" direction="in"/>
+          </method>
+          <signal name="Seeked">
+            <arg name="Position" type="x"/>
+          </signal>
+          <property name="Volume" type="d" access="readwrite"/>
+          <property name="PlaybackStatus" type="s" access="read">
+            <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="true"/>
+          </property>
+        </interface>
+    "#;
+
+    fn exported() -> ExportedObjects {
+        let objects = ExportedObjects::default();
+        let properties = serde_json::from_value(serde_json::json!({
+            "org.mpris.MediaPlayer2.Player": {"Volume": 0.5, "PlaybackStatus": "Playing"}
+        }))
+        .unwrap();
+        objects
+            .export(
+                OwnedObjectPath::try_from("/org/mpris/MediaPlayer2").unwrap(),
+                vec![
+                    InterfaceDefinition::Xml(XML.into()),
+                    serde_json::from_value(serde_json::json!({
+                        "name": "org.mpris.MediaPlayer2",
+                        "methods": [{"name": "Raise"}],
+                        "properties": [{"name": "Identity", "signature": "s", "access": "read"}]
+                    }))
+                    .unwrap(),
+                ],
+                properties,
+            )
+            .unwrap();
+        objects
+    }
+
+    #[test]
+    fn introspection_contains_exported_interfaces_and_ancestors() {
+        let objects = exported();
+        let objects = objects.objects.lock().unwrap();
+        let path = |path: &'static str| ObjectPath::try_from(path).unwrap();
+        let xml = introspect(&objects, &path("/org/mpris/MediaPlayer2")).unwrap();
+        let node = Node::from_reader(xml.as_bytes()).unwrap();
+        let names: Vec<_> = node
+            .interfaces()
+            .iter()
+            .map(|i| i.name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                interfaces::INTROSPECTABLE,
+                interfaces::PROPERTIES,
+                "org.mpris.MediaPlayer2.Player",
+                "org.mpris.MediaPlayer2"
+            ]
+        );
+        let player = &node.interfaces()[2];
+        assert_eq!(player.methods()[0].args()[0].ty().to_string(), "x");
+        assert_eq!(player.properties()[1].annotations().len(), 1);
+        let xml = introspect(&objects, &path("/org")).unwrap();
+        let node = Node::from_reader(xml.as_bytes()).unwrap();
+        assert!(node.interfaces().is_empty());
+        assert_eq!(node.nodes()[0].name(), Some("mpris"));
+        assert!(introspect(&objects, &path("/com")).is_err());
+    }
+
+    #[test]
+    fn property_values_are_typed_by_definition() {
+        let objects = exported();
+        let path = OwnedObjectPath::try_from("/org/mpris/MediaPlayer2").unwrap();
+        let interface = OwnedInterfaceName::try_from("org.mpris.MediaPlayer2.Player").unwrap();
+        let name = OwnedPropertyName::try_from("Volume").unwrap();
+        let value = objects
+            .set_property(&path, &interface, &name, serde_json::json!(1))
+            .unwrap();
+        assert_eq!(value, zvariant::Value::F64(1.0));
+        assert_eq!(
+            objects.value(&path, &interface, &name).unwrap(),
+            zvariant::Value::F64(1.0)
+        );
+        let name = OwnedPropertyName::try_from("Missing").unwrap();
+        assert!(objects
+            .set_property(&path, &interface, &name, serde_json::json!(1))
+            .is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use crate::value::{Array, ContainerValue, Dict, PrimitiveValue, Value};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// Predicate on the decoded signal argument.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ArgFilter {
+    pub arg: usize,
+    /// Dict keys and array or structure indexes of the nested value, the variants are unwrapped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path: Vec<PathSegment>,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+#[serde(untagged)]
+pub enum PathSegment {
+    Index(usize),
+    Key(String),
+}
+
+/// Condition on the value, only `exists` matches the missing values.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum Condition {
+    Exists(bool),
+    Eq(Operand),
+    Ne(Operand),
+    Gt(Decimal),
+    Ge(Decimal),
+    Lt(Decimal),
+    Le(Decimal),
+}
+
+// `Decimal` is deserialized from the JSON strings too, so the strings are tried first
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+#[serde(untagged)]
+pub enum Operand {
+    Bool(bool),
+    String(String),
+    Number(Decimal),
+}
+
+/// Returns true if the signal arguments match all filters.
+pub fn matches(filters: &[ArgFilter], args: &[Value]) -> bool {
+    filters.iter().all(|filter| filter.matches(args))
+}
+
+impl ArgFilter {
+    fn matches(&self, args: &[Value]) -> bool {
+        let value = args.get(self.arg).and_then(|arg| {
+            self.path
+                .iter()
+                .try_fold(arg, |value, segment| child(value, segment))
+        });
+        self.condition.matches(value.map(unwrap_variant))
+    }
+}
+
+impl Condition {
+    fn matches(&self, value: Option<&Value>) -> bool {
+        let Some(value) = value else {
+            return *self == Condition::Exists(false);
+        };
+        match self {
+            Condition::Exists(exists) => *exists,
+            Condition::Eq(operand) => operand.equals(value),
+            Condition::Ne(operand) => !operand.equals(value),
+            Condition::Gt(number) => to_number(value).is_some_and(|value| value > *number),
+            Condition::Ge(number) => to_number(value).is_some_and(|value| value >= *number),
+            Condition::Lt(number) => to_number(value).is_some_and(|value| value < *number),
+            Condition::Le(number) => to_number(value).is_some_and(|value| value <= *number),
+        }
+    }
+}
+
+impl Operand {
+    fn equals(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Operand::Bool(operand), Value::Primitive(PrimitiveValue::Bool(value))) => {
+                operand == value
+            }
+            (Operand::Number(operand), value) => to_number(value) == Some(*operand),
+            (Operand::String(operand), Value::Primitive(PrimitiveValue::String(value))) => {
+                operand == value
+            }
+            (Operand::String(operand), Value::Primitive(PrimitiveValue::ObjectPath(value))) => {
+                operand == value.as_str()
+            }
+            (Operand::String(operand), Value::Primitive(PrimitiveValue::Signature(value))) => {
+                *operand == value.to_string()
+            }
+            _ => false,
+        }
+    }
+}
+
+fn child<'a>(value: &'a Value, segment: &PathSegment) -> Option<&'a Value> {
+    let Value::Container(container) = unwrap_variant(value) else {
+        return None;
+    };
+    match (container, segment) {
+        (ContainerValue::Dict(Dict::Value { value }), PathSegment::Key(key)) => value
+            .iter()
+            .find_map(|(other, value)| (plain_key(other) == *key).then_some(value)),
+        (ContainerValue::Dict(Dict::Value { value }), PathSegment::Index(index)) => value
+            .iter()
+            .find_map(|(other, value)| (plain_key(other) == index.to_string()).then_some(value)),
+        (ContainerValue::Array(Array::Value(values)), PathSegment::Index(index))
+        | (ContainerValue::Struct { value: values }, PathSegment::Index(index)) => {
+            values.get(*index)
+        }
+        _ => None,
+    }
+}
+
+// The received dict keys are in the GVariant text format, e.g. `"name"`, `uint32 7` or `byte 0x07`,
+// the filter path has the plain keys
+fn plain_key(key: &str) -> String {
+    let (annotation, text) = match key.split_once(' ') {
+        Some((annotation, text)) if ANNOTATIONS.contains(&annotation) => (Some(annotation), text),
+        _ => (None, key),
+    };
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        return serde_json::from_str(text).unwrap_or_else(|_| text[1..text.len() - 1].to_string());
+    }
+    match annotation {
+        Some("byte") => text
+            .strip_prefix("0x")
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .map_or_else(|| text.to_string(), |byte| byte.to_string()),
+        _ => text.to_string(),
+    }
+}
+
+const ANNOTATIONS: [&str; 8] = [
+    "byte",
+    "int16",
+    "uint16",
+    "uint32",
+    "int64",
+    "uint64",
+    "objectpath",
+    "signature",
+];
+
+fn unwrap_variant(mut value: &Value) -> &Value {
+    while let Value::Container(ContainerValue::Variant { value: inner }) = value {
+        value = inner;
+    }
+    value
+}
+
+fn to_number(value: &Value) -> Option<Decimal> {
+    let Value::Primitive(value) = value else {
+        return None;
+    };
+    match value {
+        PrimitiveValue::U8(value) => Some((*value).into()),
+        PrimitiveValue::I16(value) => Some((*value).into()),
+        PrimitiveValue::U16(value) => Some((*value).into()),
+        PrimitiveValue::I32(value) => Some((*value).into()),
+        PrimitiveValue::U32(value) => Some((*value).into()),
+        PrimitiveValue::I64(value) => Some((*value).into()),
+        PrimitiveValue::U64(value) => Some((*value).into()),
+        PrimitiveValue::F64(value) => Some(*value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn filters(json: serde_json::Value) -> Vec<ArgFilter> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    // Arguments of the `org.freedesktop.DBus.Properties.PropertiesChanged` signal
+    fn properties_changed() -> Vec<Value> {
+        let connected = ContainerValue::Variant {
+            value: Box::new(PrimitiveValue::Bool(true).into()),
+        };
+        let rssi = ContainerValue::Variant {
+            value: Box::new(PrimitiveValue::I16(-60).into()),
+        };
+        let changed = Dict::Value {
+            value: HashMap::from([
+                ("\"Connected\"".to_string(), connected.into()),
+                ("\"RSSI\"".to_string(), rssi.into()),
+            ]),
+        };
+        vec![
+            PrimitiveValue::String("org.bluez.Device1".into()).into(),
+            ContainerValue::Dict(changed).into(),
+            ContainerValue::Array(Array::Value(Vec::new())).into(),
+        ]
+    }
+
+    #[test]
+    fn nested_values_match() {
+        let args = properties_changed();
+        let filters = filters(serde_json::json!([
+            {"arg": 0, "eq": "org.bluez.Device1"},
+            {"arg": 1, "path": ["Connected"], "exists": true},
+            {"arg": 1, "path": ["Connected"], "eq": true},
+            {"arg": 1, "path": ["RSSI"], "gt": -70},
+            {"arg": 1, "path": ["Name"], "exists": false},
+        ]));
+        assert!(matches(&filters, &args));
+    }
+
+    #[test]
+    fn any_failed_filter_rejects() {
+        let args = properties_changed();
+        for filter in [
+            serde_json::json!({"arg": 0, "ne": "org.bluez.Device1"}),
+            serde_json::json!({"arg": 1, "path": ["Name"], "exists": true}),
+            serde_json::json!({"arg": 1, "path": ["RSSI"], "le": -70.5}),
+            serde_json::json!({"arg": 1, "path": ["Name"], "ne": "Phone"}),
+            serde_json::json!({"arg": 0, "gt": 1}),
+            serde_json::json!({"arg": 2, "path": [0], "exists": true}),
+            serde_json::json!({"arg": 3, "exists": true}),
+        ] {
+            let filters = filters(serde_json::json!([filter]));
+            assert!(!matches(&filters, &args), "{:?}", filters);
+        }
+    }
+
+    #[test]
+    fn received_dict_keys_are_plain() {
+        let dict = |key: &str| {
+            let value = HashMap::from([(key.to_string(), PrimitiveValue::Bool(true).into())]);
+            vec![ContainerValue::Dict(Dict::Value { value }).into()]
+        };
+        for (key, path) in [
+            ("\"name\"", serde_json::json!("name")),
+            ("\"a \\\"b\\\"\"", serde_json::json!("a \"b\"")),
+            ("uint32 7", serde_json::json!(7)),
+            ("-7", serde_json::json!("-7")),
+            ("byte 0x0a", serde_json::json!(10)),
+            (
+                "objectpath \"/org/example\"",
+                serde_json::json!("/org/example"),
+            ),
+        ] {
+            let filters = filters(serde_json::json!([{"arg": 0, "path": [path], "eq": true}]));
+            assert!(matches(&filters, &dict(key)), "{}", key);
+        }
+    }
+
+    #[test]
+    fn numeric_strings_are_strings() {
+        let args = vec![PrimitiveValue::String("123".into()).into()];
+        let filters = filters(serde_json::json!([{"arg": 0, "eq": "123"}]));
+        assert_eq!(
+            filters[0].condition,
+            Condition::Eq(Operand::String("123".into()))
+        );
+        assert!(matches(&filters, &args));
+        let numbers = vec![PrimitiveValue::U32(123).into()];
+        assert!(!matches(&filters, &numbers));
+    }
+}
@@ -0,0 +1,3 @@
+pub const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+pub const OBJECT_MANAGER: &str = "org.freedesktop.DBus.ObjectManager";
+pub const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
@@ -0,0 +1,549 @@
+use crate::interfaces;
+use crate::value::{BodySignature, ValueType};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::task::AbortHandle;
+use tokio_stream::StreamExt;
+use tracing::{instrument, trace};
+use zbus::names::{OwnedBusName, OwnedInterfaceName, OwnedMemberName, OwnedPropertyName};
+use zbus_xml::{
+    Annotation, Arg, ArgDirection, Interface, Method, Node, Property, PropertyAccess, Signal,
+};
+use zvariant::OwnedObjectPath;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Introspection data cannot be parsed: {0}")]
+    InvalidXml(#[from] zbus_xml::Error),
+    #[error("The object '{path}' doesn't implement interface '{interface}'")]
+    InterfaceNotFound {
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+    },
+    #[error("The object '{path}' doesn't have method '{method}'")]
+    MethodNotFound {
+        path: OwnedObjectPath,
+        method: OwnedMemberName,
+    },
+    #[error("The interface '{interface}' of '{path}' doesn't have property '{property}'")]
+    PropertyNotFound {
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+        property: OwnedPropertyName,
+    },
+    #[error("The method '{method}' is implemented by several interfaces of '{path}', the interface must be specified")]
+    AmbiguousMethod {
+        path: OwnedObjectPath,
+        method: OwnedMemberName,
+    },
+}
+
+type IntrospectionKey = (Option<OwnedBusName>, OwnedObjectPath);
+
+/// JSON friendly description of the introspected object.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectDescription {
+    interfaces: Vec<InterfaceDescription>,
+    nodes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceDescription {
+    name: String,
+    methods: Vec<MethodDescription>,
+    signals: Vec<SignalDescription>,
+    properties: Vec<PropertyDescription>,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MethodDescription {
+    name: String,
+    in_args: Vec<ArgDescription>,
+    out_args: Vec<ArgDescription>,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignalDescription {
+    name: String,
+    args: Vec<ArgDescription>,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PropertyDescription {
+    name: String,
+    #[serde(flatten)]
+    type_: TypeDescription,
+    access: PropertyAccess,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArgDescription {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(flatten)]
+    type_: TypeDescription,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TypeDescription {
+    #[serde(rename = "type")]
+    value_type: ValueType,
+    signature: zvariant::Signature,
+}
+
+/// Parsed introspection data of the objects, cached until the destination owner is changed.
+#[derive(Debug, Default)]
+pub struct IntrospectionCache {
+    nodes: Arc<Mutex<HashMap<IntrospectionKey, Arc<Node<'static>>>>>,
+    owner_watchers: Mutex<HashMap<OwnedBusName, AbortHandle>>,
+}
+
+impl IntrospectionCache {
+    #[instrument(skip(connection))]
+    pub async fn node(
+        &self,
+        connection: &zbus::Connection,
+        destination: Option<&OwnedBusName>,
+        path: &OwnedObjectPath,
+    ) -> crate::Result<Arc<Node<'static>>> {
+        let key = (destination.cloned(), path.clone());
+        if let Some(node) = self.nodes.lock().unwrap().get(&key) {
+            return Ok(node.clone());
+        }
+        if let Some(destination) = destination {
+            self.watch_owner(connection, destination).await?;
+        }
+        let reply = connection
+            .call_method(
+                destination,
+                path,
+                Some(interfaces::INTROSPECTABLE),
+                "Introspect",
+                &(),
+            )
+            .await?;
+        let xml: String = reply.body().deserialize()?;
+        trace!("Introspection data received: {}", xml);
+        let node = Arc::new(Node::from_reader(xml.as_bytes()).map_err(Error::from)?);
+        self.nodes.lock().unwrap().insert(key, node.clone());
+        Ok(node)
+    }
+
+    pub async fn describe(
+        &self,
+        connection: &zbus::Connection,
+        destination: Option<&OwnedBusName>,
+        path: &OwnedObjectPath,
+    ) -> crate::Result<ObjectDescription> {
+        let node = self.node(connection, destination, path).await?;
+        Ok(node.as_ref().into())
+    }
+
+    /// Resolves the interface and the input arguments signature of the method.
+    /// If the interface is not specified, the interface that has the method is used.
+    pub async fn method_signature(
+        &self,
+        connection: &zbus::Connection,
+        destination: Option<&OwnedBusName>,
+        path: &OwnedObjectPath,
+        interface: Option<&OwnedInterfaceName>,
+        method: &OwnedMemberName,
+    ) -> crate::Result<(OwnedInterfaceName, BodySignature)> {
+        let node = self.node(connection, destination, path).await?;
+        Ok(resolve_method_signature(&node, path, interface, method)?)
+    }
+
+    /// Resolves the type of the property.
+    pub async fn property_signature(
+        &self,
+        connection: &zbus::Connection,
+        destination: Option<&OwnedBusName>,
+        path: &OwnedObjectPath,
+        interface: &OwnedInterfaceName,
+        property: &OwnedPropertyName,
+    ) -> crate::Result<zvariant::Signature> {
+        let node = self.node(connection, destination, path).await?;
+        Ok(resolve_property_signature(
+            &node, path, interface, property,
+        )?)
+    }
+
+    // The cached data is removed when the destination name is acquired by another connection
+    async fn watch_owner(
+        &self,
+        connection: &zbus::Connection,
+        destination: &OwnedBusName,
+    ) -> crate::Result<()> {
+        if self
+            .owner_watchers
+            .lock()
+            .unwrap()
+            .contains_key(destination)
+        {
+            return Ok(());
+        }
+        let dbus = zbus::fdo::DBusProxy::new(connection).await?;
+        let mut owner_changes = dbus
+            .receive_name_owner_changed_with_args(&[(0, destination.as_str())])
+            .await?;
+        let nodes = self.nodes.clone();
+        let name = destination.clone();
+        let watcher = tokio::spawn(async move {
+            while owner_changes.next().await.is_some() {
+                trace!("Owner of '{}' is changed", name);
+                invalidate(&nodes, &name);
+            }
+        });
+        if let Some(previous) = self
+            .owner_watchers
+            .lock()
+            .unwrap()
+            .insert(destination.clone(), watcher.abort_handle())
+        {
+            previous.abort();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for IntrospectionCache {
+    fn drop(&mut self) {
+        for watcher in self.owner_watchers.lock().unwrap().values() {
+            watcher.abort();
+        }
+    }
+}
+
+impl From<&Node<'_>> for ObjectDescription {
+    fn from(node: &Node<'_>) -> Self {
+        Self {
+            interfaces: node.interfaces().iter().map(Into::into).collect(),
+            nodes: node
+                .nodes()
+                .iter()
+                .filter_map(|node| node.name().map(ToString::to_string))
+                .collect(),
+        }
+    }
+}
+
+impl From<&Interface<'_>> for InterfaceDescription {
+    fn from(interface: &Interface<'_>) -> Self {
+        Self {
+            name: interface.name().to_string(),
+            methods: interface.methods().iter().map(Into::into).collect(),
+            signals: interface.signals().iter().map(Into::into).collect(),
+            properties: interface.properties().iter().map(Into::into).collect(),
+            annotations: annotations(interface.annotations()),
+        }
+    }
+}
+
+impl From<&Method<'_>> for MethodDescription {
+    fn from(method: &Method<'_>) -> Self {
+        let (out_args, in_args) = method
+            .args()
+            .iter()
+            .partition::<Vec<_>, _>(|arg| arg.direction() == Some(ArgDirection::Out));
+        Self {
+            name: method.name().to_string(),
+            in_args: in_args.into_iter().map(Into::into).collect(),
+            out_args: out_args.into_iter().map(Into::into).collect(),
+            annotations: annotations(method.annotations()),
+        }
+    }
+}
+
+impl From<&Signal<'_>> for SignalDescription {
+    fn from(signal: &Signal<'_>) -> Self {
+        Self {
+            name: signal.name().to_string(),
+            args: signal.args().iter().map(Into::into).collect(),
+            annotations: annotations(signal.annotations()),
+        }
+    }
+}
+
+impl From<&Property<'_>> for PropertyDescription {
+    fn from(property: &Property<'_>) -> Self {
+        Self {
+            name: property.name().to_string(),
+            type_: property.ty().inner().into(),
+            access: property.access(),
+            annotations: annotations(property.annotations()),
+        }
+    }
+}
+
+impl From<&Arg> for ArgDescription {
+    fn from(arg: &Arg) -> Self {
+        Self {
+            name: arg.name().map(ToString::to_string),
+            type_: arg.ty().inner().into(),
+            annotations: annotations(arg.annotations()),
+        }
+    }
+}
+
+impl From<&zvariant::Signature> for TypeDescription {
+    fn from(signature: &zvariant::Signature) -> Self {
+        Self {
+            value_type: signature.into(),
+            signature: signature.clone(),
+        }
+    }
+}
+
+pub(crate) fn annotations(annotations: &[Annotation]) -> BTreeMap<String, String> {
+    annotations
+        .iter()
+        .map(|a| (a.name().to_string(), a.value().to_string()))
+        .collect()
+}
+
+fn invalidate(
+    nodes: &Mutex<HashMap<IntrospectionKey, Arc<Node<'static>>>>,
+    destination: &OwnedBusName,
+) {
+    nodes
+        .lock()
+        .unwrap()
+        .retain(|(d, _), _| d.as_ref() != Some(destination));
+}
+
+fn resolve_method_signature(
+    node: &Node,
+    path: &OwnedObjectPath,
+    interface: Option<&OwnedInterfaceName>,
+    method: &OwnedMemberName,
+) -> Result<(OwnedInterfaceName, BodySignature), Error> {
+    let has_method = |interface: &&Interface| {
+        interface
+            .methods()
+            .iter()
+            .any(|m| m.name() == method.as_ref())
+    };
+    let interface = match interface {
+        Some(interface) => node
+            .interfaces()
+            .iter()
+            .find(|i| i.name() == interface.as_ref())
+            .ok_or_else(|| Error::InterfaceNotFound {
+                path: path.clone(),
+                interface: interface.clone(),
+            })?,
+        None => {
+            let mut interfaces = node.interfaces().iter().filter(has_method);
+            let interface = interfaces.next().ok_or_else(|| Error::MethodNotFound {
+                path: path.clone(),
+                method: method.clone(),
+            })?;
+            if interfaces.next().is_some() {
+                return Err(Error::AmbiguousMethod {
+                    path: path.clone(),
+                    method: method.clone(),
+                });
+            }
+            interface
+        }
+    };
+    let method = interface
+        .methods()
+        .iter()
+        .find(|m| m.name() == method.as_ref())
+        .ok_or_else(|| Error::MethodNotFound {
+            path: path.clone(),
+            method: method.clone(),
+        })?;
+    let signature: Vec<zvariant::Signature> = method
+        .args()
+        .iter()
+        .filter(|arg| arg.direction() != Some(ArgDirection::Out))
+        .map(|arg| arg.ty().inner().clone())
+        .collect();
+    Ok((interface.name().into(), signature.into()))
+}
+
+fn resolve_property_signature(
+    node: &Node,
+    path: &OwnedObjectPath,
+    interface: &OwnedInterfaceName,
+    property: &OwnedPropertyName,
+) -> Result<zvariant::Signature, Error> {
+    let property = node
+        .interfaces()
+        .iter()
+        .find(|i| i.name() == interface.as_ref())
+        .ok_or_else(|| Error::InterfaceNotFound {
+            path: path.clone(),
+            interface: interface.clone(),
+        })?
+        .properties()
+        .iter()
+        .find(|p| p.name() == property.as_ref())
+        .ok_or_else(|| Error::PropertyNotFound {
+            path: path.clone(),
+            interface: interface.clone(),
+            property: property.clone(),
+        })?;
+    Ok(property.ty().inner().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XML: &str = r#"
+        <node>
+          <interface name="org.kde.ScreenBrightness.Display">
+            <method name="SetBrightness">
+              <arg name="brightness" type="i" direction="in"/>
+              <arg name="flags" type="u" direction="in"/>
+            </method>
+            <method name="Reset"/>
+            <property name="Brightness" type="i" access="readwrite"/>
+          </interface>
+          <interface name="org.example.First">
+            <method name="Reset"/>
+          </interface>
+          <interface name="org.example.Second">
+            <method name="Get">
+              <arg name="keys" type="as"/>
+              <arg name="values" type="a{sv}" direction="out"/>
+            </method>
+          </interface>
+        </node>
+    "#;
+
+    fn resolve(interface: Option<&str>, method: &str) -> Result<(String, String), Error> {
+        let node = Node::from_reader(XML.as_bytes()).unwrap();
+        let interface = interface.map(|i| OwnedInterfaceName::try_from(i).unwrap());
+        let (interface, signature) = resolve_method_signature(
+            &node,
+            &OwnedObjectPath::try_from("/").unwrap(),
+            interface.as_ref(),
+            &OwnedMemberName::try_from(method).unwrap(),
+        )?;
+        Ok((interface.to_string(), signature.to_string()))
+    }
+
+    #[test]
+    fn method_signature_with_interface() {
+        let (interface, signature) =
+            resolve(Some("org.kde.ScreenBrightness.Display"), "SetBrightness").unwrap();
+        assert_eq!(interface, "org.kde.ScreenBrightness.Display");
+        assert_eq!(signature, "iu");
+        assert!(matches!(
+            resolve(Some("org.example.Missing"), "SetBrightness"),
+            Err(Error::InterfaceNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn method_signature_without_interface() {
+        let (interface, signature) = resolve(None, "Get").unwrap();
+        assert_eq!(interface, "org.example.Second");
+        assert_eq!(signature, "as");
+        assert!(matches!(
+            resolve(None, "Reset"),
+            Err(Error::AmbiguousMethod { .. })
+        ));
+        assert!(matches!(
+            resolve(None, "Missing"),
+            Err(Error::MethodNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn property_signature() {
+        let node = Node::from_reader(XML.as_bytes()).unwrap();
+        let path = OwnedObjectPath::try_from("/").unwrap();
+        let resolve = |interface: &str, property: &str| {
+            resolve_property_signature(
+                &node,
+                &path,
+                &OwnedInterfaceName::try_from(interface).unwrap(),
+                &OwnedPropertyName::try_from(property).unwrap(),
+            )
+        };
+        assert_eq!(
+            resolve("org.kde.ScreenBrightness.Display", "Brightness")
+                .unwrap()
+                .to_string(),
+            "i"
+        );
+        assert!(matches!(
+            resolve("org.example.First", "Brightness"),
+            Err(Error::PropertyNotFound { .. })
+        ));
+        assert!(matches!(
+            resolve("org.example.Missing", "Brightness"),
+            Err(Error::InterfaceNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn describe_object() {
+        let node = Node::from_reader(
+            r#"
+            <node>
+              <interface name="org.example.Player">
+                <method name="Seek">
+                  <arg name="offset" type="x" direction="in"/>
+                  <annotation name="org.freedesktop.DBus.Method.NoReply" value="true"/>
+                </method>
+                <signal name="Seeked">
+                  <arg name="position" type="x"/>
+                </signal>
+                <property name="Metadata" type="a{sv}" access="read"/>
+              </interface>
+              <node name="Track1"/>
+            </node>
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+        let description: ObjectDescription = (&node).into();
+        assert_eq!(
+            serde_json::to_value(&description).unwrap(),
+            serde_json::json!({
+                "interfaces": [{
+                    "name": "org.example.Player",
+                    "methods": [{
+                        "name": "Seek",
+                        "inArgs": [{"name": "offset", "type": "i64", "signature": "x", "annotations": {}}],
+                        "outArgs": [],
+                        "annotations": {"org.freedesktop.DBus.Method.NoReply": "true"}
+                    }],
+                    "signals": [{
+                        "name": "Seeked",
+                        "args": [{"name": "position", "type": "i64", "signature": "x", "annotations": {}}],
+                        "annotations": {}
+                    }],
+                    "properties": [{
+                        "name": "Metadata",
+                        "type": {"dict": {"keyType": "string", "valueType": "variant"}},
+                        "signature": "a{sv}",
+                        "access": "read",
+                        "annotations": {}
+                    }],
+                    "annotations": {}
+                }],
+                "nodes": ["Track1"]
+            })
+        );
+    }
+}
@@ -1,26 +1,46 @@
+use crate::address::{AllowedAddress, DBusAddress};
 use crate::signal_handler::SignalHandler;
 use crate::web_socket_message_handler::WebSocketMessageHandler;
 use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{Query, WebSocketUpgrade};
-use axum::http::header;
+use axum::extract::{Query, State, WebSocketUpgrade};
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::get;
 use axum::Router;
 use clap::Parser;
 use error::{Error, RequestError};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
 use log::warn;
-use message::OutputMessage;
-use serde::Deserialize;
+use message::{OutputMessage, TaggedOutputMessage};
+use outbound::{Outbound, OutboundQueue, OverflowPolicy};
+use pool::ConnectionPool;
+use serde::{Deserialize, Serialize};
 use state::WebSocketState;
 use std::fmt::Debug;
 use std::net::SocketAddr;
 use std::ops::ControlFlow;
-use thiserror::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::{error, info, instrument};
 
+mod address;
+mod delivery;
 mod error;
+mod export;
+mod filter;
+mod interfaces;
+mod introspection;
 mod message;
+mod monitor;
+mod names;
+mod object_manager;
+mod outbound;
+mod pool;
+mod properties;
 mod signal_handler;
+mod signals;
 mod state;
 mod value;
 mod web_socket_message_handler;
@@ -36,6 +56,63 @@ struct Args {
 
     #[arg(short, long, value_enum, default_value = "Warn")]
     log_level: tracing::level_filters::LevelFilter,
+
+    /// Maximum number of requests that can be processed concurrently for each WebSocket
+    #[arg(long, default_value_t = 64)]
+    max_pending_requests: usize,
+
+    /// Default timeout of the DBus method calls in milliseconds
+    #[arg(long, default_value_t = 25000)]
+    method_timeout_ms: u64,
+
+    /// Maximum number of subscription events waiting to be sent to each WebSocket
+    #[arg(long, default_value_t = 1024)]
+    outbound_queue_size: usize,
+
+    /// Action when the outbound queue is full, the replies to the requests are never dropped
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    overflow_policy: OverflowPolicy,
+
+    /// DBus address the clients are allowed to connect to, only 'unix' and 'tcp' addresses are supported.
+    /// The trailing '*' of the socket path or the abstract name matches any suffix.
+    /// Only the session and system buses are available if no addresses are allowed
+    #[arg(long = "allowed-address")]
+    allowed_addresses: Vec<AllowedAddress>,
+}
+
+#[derive(Debug, Clone)]
+struct ServerConfig {
+    max_pending_requests: usize,
+    method_timeout: Duration,
+    outbound_queue_size: usize,
+    overflow_policy: OverflowPolicy,
+    allowed_addresses: Vec<AllowedAddress>,
+}
+
+impl ServerConfig {
+    fn is_address_allowed(&self, address: &DBusAddress) -> bool {
+        self.allowed_addresses
+            .iter()
+            .any(|allowed| allowed.matches(address))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ServerState {
+    config: ServerConfig,
+    pool: Arc<ConnectionPool>,
+}
+
+impl From<&Args> for ServerConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            max_pending_requests: args.max_pending_requests,
+            method_timeout: Duration::from_millis(args.method_timeout_ms),
+            outbound_queue_size: args.outbound_queue_size,
+            overflow_policy: args.overflow_policy,
+            allowed_addresses: args.allowed_addresses.clone(),
+        }
+    }
 }
 
 #[tokio::main]
@@ -49,16 +126,19 @@ async fn main() {
 
     info!("Server starting with arguments: {:?}", args);
 
-    listen(args.address).await;
+    listen(args.address, (&args).into()).await;
 }
 
 #[instrument]
-async fn listen(address: SocketAddr) {
+async fn listen(address: SocketAddr, config: ServerConfig) {
     let app = Router::new()
-        .with_state(WebSocketParameters::default())
         .route("/", get(|| async { Redirect::permanent("/api") }))
         .route("/api", get(asyncapi_schema_handler))
-        .route("/ws/v1", get(web_socket_handler));
+        .route("/ws/v1", get(web_socket_handler))
+        .with_state(ServerState {
+            config,
+            pool: Arc::default(),
+        });
     let listener = tokio::net::TcpListener::bind(address)
         .await
         .unwrap_or_else(|e| {
@@ -76,17 +156,78 @@ async fn listen(address: SocketAddr) {
 }
 
 #[derive(Default, Debug, Deserialize, Clone)]
-struct WebSocketParameters {
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WebSocketParameters {
     #[serde(default)]
     connection: DBusConnectionTarget,
+    /// DBus address used instead of the connection target, it must be allowed by the server
+    #[serde(default)]
+    address: Option<String>,
+    /// Connect to the DBus server at the address directly, without the message bus
+    #[serde(default)]
+    peer_to_peer: bool,
+    /// Use the connection shared with other WebSockets with the same parameters
+    #[serde(default)]
+    shared: bool,
+}
+
+impl WebSocketParameters {
+    fn validate(&self, config: &ServerConfig) -> std::result::Result<(), (StatusCode, String)> {
+        let Some(address) = &self.address else {
+            if self.peer_to_peer || self.connection == DBusConnectionTarget::Address {
+                let message = "DBus address is required";
+                return Err((StatusCode::BAD_REQUEST, message.into()));
+            }
+            return Ok(());
+        };
+        let parsed = DBusAddress::from_str(address)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        if !config.is_address_allowed(&parsed) {
+            let message = format!("DBus address '{}' is not allowed", address);
+            return Err((StatusCode::FORBIDDEN, message));
+        }
+        Ok(())
+    }
+
+    /// Default connection of the messages, the address is used instead of the connection target.
+    pub(crate) fn target(&self) -> DBusConnectionTarget {
+        match self.address {
+            Some(_) => DBusConnectionTarget::Address,
+            None => self.connection,
+        }
+    }
+
+    /// Parameters of the connection to the target, only the address target uses the address.
+    pub(crate) fn for_target(&self, target: DBusConnectionTarget) -> Result<Self> {
+        match target {
+            DBusConnectionTarget::Address if self.address.is_none() => Err(
+                Error::UnsupportedFormat("DBus address is not set for the WebSocket".into()),
+            ),
+            DBusConnectionTarget::Address => Ok(Self {
+                connection: target,
+                ..self.clone()
+            }),
+            _ => Ok(Self {
+                connection: target,
+                address: None,
+                peer_to_peer: false,
+                shared: self.shared,
+            }),
+        }
+    }
 }
 
-#[derive(Default, Debug, Deserialize, Copy, Clone)]
-#[serde(rename_all_fields = "camelCase")]
-enum DBusConnectionTarget {
+/// DBus connection of the WebSocket, every message can be sent to any of them.
+#[derive(Default, Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum DBusConnectionTarget {
     #[default]
+    #[serde(alias = "Session")]
     Session,
+    #[serde(alias = "System")]
     System,
+    /// Connection to the address of the WebSocket parameters
+    Address,
 }
 
 async fn asyncapi_schema_handler() -> impl IntoResponse {
@@ -99,11 +240,16 @@ async fn asyncapi_schema_handler() -> impl IntoResponse {
 
 #[instrument]
 async fn web_socket_handler(
+    State(ServerState { config, pool }): State<ServerState>,
     Query(params): Query<WebSocketParameters>,
     ws: WebSocketUpgrade,
 ) -> Response {
+    if let Err(rejection) = params.validate(&config) {
+        warn!("WebSocket connection rejected: {}", rejection.1);
+        return rejection.into_response();
+    }
     ws.on_failed_upgrade(|err| error!("WebSocket initialization failed: {}", err))
-        .on_upgrade(move |ws| handle_web_socket_upgrade(params.clone(), ws))
+        .on_upgrade(move |ws| handle_web_socket_upgrade(config, pool, params, ws))
 }
 
 pub(crate) trait WebSocketEventHandler<'a, T>
@@ -115,47 +261,78 @@ where
 }
 
 #[instrument]
-async fn handle_web_socket_upgrade(params: WebSocketParameters, mut socket: WebSocket) {
-    let dbus_connection = match dbus_connection(&params).await {
-        Ok(connection) => connection,
-        Err(err) => {
-            error!("Cannot connect to the Session bus: {}", err);
-            return;
-        }
-    };
-    let state = WebSocketState::default();
-    let web_socket_message_handler = WebSocketMessageHandler::new(&dbus_connection, &state);
-    let signal_handler = SignalHandler::default();
+async fn handle_web_socket_upgrade(
+    config: ServerConfig,
+    pool: Arc<ConnectionPool>,
+    params: WebSocketParameters,
+    socket: WebSocket,
+) {
+    let (sink, mut stream) = socket.split();
+    let outbound = Arc::new(OutboundQueue::new(
+        config.outbound_queue_size,
+        config.overflow_policy,
+    ));
+    let writer = tokio::spawn(write_output_messages(sink, outbound.clone()));
+    let state = Arc::new(WebSocketState::default());
+    let web_socket_message_handler =
+        WebSocketMessageHandler::new(state.clone(), pool.clone(), config, params);
+    let signal_handler = SignalHandler::new(state.clone());
 
     loop {
-        let control = tokio::select! {
-            msg = next_web_socket_message(&mut socket) => {
-                web_socket_message_handler.handle(msg).await
+        // The messages are tagged with the connection they are received from
+        let (connection, control) = tokio::select! {
+            msg = next_web_socket_message(&mut stream) => {
+                (None, web_socket_message_handler.handle(msg).await)
+            },
+            Some((connection, result)) = state.requests().next() => {
+                (Some(connection), web_socket_message_handler.handle(result).await)
             },
             Some(signal) = state.signals().next() => {
-                signal_handler.handle(signal).await
-            }
+                (Some(signal.0.connection), signal_handler.handle(signal).await)
+            },
+            Some(routed) = state.routed().next() => {
+                (Some(routed.0), signal_handler.handle(routed).await)
+            },
+            _ = outbound.overflowed() => break,
         };
         match control {
             ControlFlow::Continue(Some(msg)) => {
-                let _ = send_output_message(&mut socket, &msg).await;
+                outbound.push(TaggedOutputMessage::new(connection, msg))
             }
             ControlFlow::Continue(None) => {}
             ControlFlow::Break(msg) => {
                 if let Some(msg) = msg {
-                    let _ = send_output_message(&mut socket, &msg).await;
+                    outbound.push(TaggedOutputMessage::new(connection, msg));
                 }
                 break;
             }
         }
     }
 
-    dbus_connection.graceful_shutdown().await;
+    outbound.close();
+    state.requests().abort_all();
+    // The aborted requests must not open the connections after they are closed
+    while state.requests().next().await.is_some() {}
+    let _ = writer.await;
+    let buses = state.take_buses().await;
+    // The connections are closed after all their references are dropped, including the streams
+    drop(web_socket_message_handler);
+    drop(signal_handler);
+    drop(state);
+    for bus in buses {
+        // The names and the pooled connection are released even if the connection is still referenced
+        let unused = bus.close(&pool).await;
+        match Arc::into_inner(bus) {
+            Some(bus) if unused => bus.shutdown().await,
+            Some(_) => {}
+            None => warn!("DBus connection is still in use after the WebSocket is closed"),
+        }
+    }
 }
 
 #[instrument]
-async fn next_web_socket_message(socket: &mut WebSocket) -> Result<Option<Message>> {
-    match socket.recv().await {
+async fn next_web_socket_message(stream: &mut SplitStream<WebSocket>) -> Result<Option<Message>> {
+    match stream.next().await {
         Some(Ok(msg)) => Ok(Some(msg)),
         Some(Err(err)) => {
             warn!(
@@ -171,16 +348,94 @@ async fn next_web_socket_message(socket: &mut WebSocket) -> Result<Option<Messag
     }
 }
 
-async fn send_output_message(socket: &mut WebSocket, output_message: &OutputMessage) -> Result<()> {
+// The messages are sent by the separate task, so the slow client does not block the session loop
+async fn write_output_messages(
+    mut sink: SplitSink<WebSocket, Message>,
+    outbound: Arc<OutboundQueue>,
+) {
+    while let Some(outbound) = outbound.next().await {
+        let result = match outbound {
+            Outbound::Message(msg) => send_output_message(&mut sink, &msg).await,
+            Outbound::Close(frame) => sink
+                .send(Message::Close(Some(frame)))
+                .await
+                .map_err(Error::from),
+        };
+        if let Err(err) = result {
+            warn!("Cannot send the message to WebSocket: {}", err);
+            return;
+        }
+    }
+}
+
+async fn send_output_message(
+    sink: &mut SplitSink<WebSocket, Message>,
+    output_message: &TaggedOutputMessage,
+) -> Result<()> {
     let json = serde_json::to_string(output_message)?;
-    Ok(socket.send(Message::Text(json)).await?)
+    Ok(sink.send(Message::Text(json)).await?)
 }
 
 #[instrument]
-async fn dbus_connection(params: &WebSocketParameters) -> Result<zbus::Connection> {
-    let builder = match params.connection {
-        DBusConnectionTarget::Session => zbus::connection::Builder::session()?,
-        DBusConnectionTarget::System => zbus::connection::Builder::system()?,
+pub(crate) async fn dbus_connection(params: &WebSocketParameters) -> Result<zbus::Connection> {
+    let builder = match (params.target(), &params.address) {
+        (DBusConnectionTarget::Session, _) => zbus::connection::Builder::session()?,
+        (DBusConnectionTarget::System, _) => zbus::connection::Builder::system()?,
+        (DBusConnectionTarget::Address, Some(address)) => {
+            zbus::connection::Builder::address(address.as_str())?
+        }
+        (DBusConnectionTarget::Address, None) => {
+            return Err(Error::UnsupportedFormat("DBus address is not set".into()))
+        }
+    };
+    let builder = if params.peer_to_peer {
+        builder.p2p()
+    } else {
+        builder
     };
     Ok(builder.build().await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allowed_addresses: &[&str]) -> ServerConfig {
+        let args = allowed_addresses
+            .iter()
+            .flat_map(|address| ["--allowed-address", address]);
+        ServerConfig::from(&Args::parse_from(
+            std::iter::once("dbus-ws-proxy").chain(args),
+        ))
+    }
+
+    fn is_allowed(config: &ServerConfig, address: &str) -> bool {
+        config.is_address_allowed(&address.parse().unwrap())
+    }
+
+    #[test]
+    fn addresses_are_allowed_by_prefix() {
+        let config = config(&["unix:path=/run/user/1000/bus", "unix:path=/run/flatpak/*"]);
+        assert!(is_allowed(&config, "unix:path=/run/user/1000/bus"));
+        assert!(!is_allowed(&config, "unix:path=/run/user/1000/bus2"));
+        assert!(is_allowed(&config, "unix:path=/run/flatpak/bus"));
+        assert!(is_allowed(&config, "unix:path=/run//flatpak/./bus"));
+        assert!(!is_allowed(&config, "unix:path=/run/flatpak2/bus"));
+        assert!(!is_allowed(&config, "tcp:host=127.0.0.1,port=4000"));
+    }
+
+    #[test]
+    fn path_traversal_is_not_allowed() {
+        let config = config(&["unix:path=/run/flatpak/*"]);
+        let address = "unix:path=/run/flatpak/../../run/dbus/system_bus_socket";
+        assert!(DBusAddress::from_str(address).is_err());
+        let params = WebSocketParameters {
+            address: Some(address.into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate(&config).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
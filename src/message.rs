@@ -1,15 +1,30 @@
 use crate::error::{ErrorType, RequestError};
-use crate::value::Value;
-use crate::{Error, RequestResult};
-use serde::{Deserialize, Serialize};
-use zbus::message::Type;
+use crate::export::{ExportedProperties, InterfaceDefinition};
+use crate::filter::ArgFilter;
+use crate::introspection::ObjectDescription;
+use crate::names::{NameFlags, ReleaseNameReply, RequestNameReply};
+use crate::state::SubscriptionInfo;
+use crate::value::{BodySignature, Value};
+use crate::{DBusConnectionTarget, Error, RequestResult};
+use serde::ser::Error as _;
+use serde::ser::{Impossible, SerializeMap, SerializeStructVariant};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zbus::message::{Flags, Type};
 use zbus::names::{
-    BusName, InterfaceName, MemberName, OwnedBusName, OwnedInterfaceName, OwnedMemberName,
+    BusName, InterfaceName, MemberName, OwnedBusName, OwnedErrorName, OwnedInterfaceName,
+    OwnedMemberName, OwnedPropertyName, OwnedUniqueName, OwnedWellKnownName,
 };
+use zbus::OwnedMatchRule;
 use zvariant::{ObjectPath, OwnedObjectPath};
 
 pub type RequestId = u64;
 
+/// Id of the incoming method call of the exported object.
+pub type CallId = u64;
+pub type SubscriptionId = u64;
+
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
 pub struct MemberKey<'a> {
     #[serde(borrow)]
@@ -20,47 +35,398 @@ pub struct MemberKey<'a> {
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
-pub struct OwnedMemberKey {
-    pub destination: OwnedBusName,
+pub struct OwnedObjectKey {
+    #[serde(default)]
+    pub destination: Option<OwnedBusName>,
     pub path: OwnedObjectPath,
-    pub interface: OwnedInterfaceName,
-    pub name: OwnedMemberName,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+pub struct OwnedInterfaceKey {
+    #[serde(default)]
+    pub destination: Option<OwnedBusName>,
+    pub path: OwnedObjectPath,
+    pub interface: OwnedInterfaceName,
+}
+
+/// Signal match rule, the fields that are not set match any value.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
 pub struct OwnedSignalKey {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<OwnedBusName>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<OwnedObjectPath>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_namespace: Option<OwnedObjectPath>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<OwnedInterfaceName>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<OwnedMemberName>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<(u8, String)>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub arg_paths: Vec<(u8, OwnedObjectPath)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arg0namespace: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MethodCall {
+    #[serde(default)]
+    pub request_id: Option<RequestId>,
+    #[serde(default)]
+    pub connection: Option<DBusConnectionTarget>,
+    pub destination: Option<OwnedBusName>,
+    pub path: OwnedObjectPath,
+    pub interface: Option<OwnedInterfaceName>,
+    pub method_name: OwnedMemberName,
+    #[serde(default)]
+    pub signature: Option<BodySignature>,
+    #[serde(default)]
+    pub args: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
     #[serde(flatten)]
-    pub member: OwnedMemberKey,
+    pub flags: CallFlags,
     #[serde(default)]
-    pub args: Vec<(u8, String)>,
+    pub include_header: bool,
+}
+
+/// Delivery options of the signal subscription.
+/// The signals received within the throttle or debounce window are delivered at the end of it,
+/// only the latest ones are kept if there are too many.
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq, Hash, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DeliveryOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub throttle_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debounce_ms: Option<u64>,
+    /// Only the latest of the delayed signals with the same path, interface and name is delivered,
+    /// the properties of `PropertiesChanged` with the same interface are merged
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub latest_only: bool,
+    /// Only the signals with the arguments matching all filters are delivered
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filter: Vec<ArgFilter>,
+}
+
+/// Method call message header flags.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CallFlags {
+    pub no_reply_expected: bool,
+    pub no_auto_start: bool,
+    pub allow_interactive_authorization: bool,
+}
+
+impl CallFlags {
+    pub fn header_flags(&self) -> impl Iterator<Item = Flags> {
+        [
+            (self.no_reply_expected, Flags::NoReplyExpected),
+            (self.no_auto_start, Flags::NoAutoStart),
+            (
+                self.allow_interactive_authorization,
+                Flags::AllowInteractiveAuth,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all_fields = "camelCase")]
-pub enum InputMessage<'a> {
-    CallMethod {
+pub enum InputMessage {
+    CallMethod(MethodCall),
+    SubscribeSignal {
         #[serde(default)]
         request_id: Option<RequestId>,
-        #[serde(borrow)]
-        destination: Option<BusName<'a>>,
-        path: ObjectPath<'a>,
-        interface: Option<InterfaceName<'a>>,
-        method_name: MemberName<'a>,
         #[serde(default)]
-        args: Vec<Value>,
+        connection: Option<DBusConnectionTarget>,
+        #[serde(flatten)]
+        key: OwnedSignalKey,
+        #[serde(default)]
+        include_header: bool,
+        #[serde(flatten)]
+        delivery: DeliveryOptions,
     },
-    SubscribeSignal {
+    UnsubscribeSignal {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        subscription_id: SubscriptionId,
+    },
+    SubscribeMatchRule {
         #[serde(default)]
         request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        rule: OwnedMatchRule,
+        #[serde(default)]
+        include_header: bool,
         #[serde(flatten)]
-        key: OwnedSignalKey,
+        delivery: DeliveryOptions,
     },
-    UnsubscribeSignal {
+    UnsubscribeMatchRule {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(default)]
+        rule: Option<OwnedMatchRule>,
+        #[serde(default)]
+        subscription_id: Option<SubscriptionId>,
+    },
+    Introspect {
         #[serde(default)]
         request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        destination: Option<OwnedBusName>,
+        path: OwnedObjectPath,
+    },
+    GetProperty {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
         #[serde(flatten)]
-        key: OwnedSignalKey,
+        key: OwnedInterfaceKey,
+        name: OwnedPropertyName,
+    },
+    SetProperty {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(flatten)]
+        key: OwnedInterfaceKey,
+        name: OwnedPropertyName,
+        value: serde_json::Value,
+    },
+    GetAllProperties {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(flatten)]
+        key: OwnedInterfaceKey,
+    },
+    SubscribePropertyChanges {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(flatten)]
+        key: OwnedInterfaceKey,
+    },
+    UnsubscribePropertyChanges {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(flatten)]
+        key: OwnedInterfaceKey,
+    },
+    SubscribeObjectManager {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(flatten)]
+        key: OwnedObjectKey,
+        #[serde(default)]
+        properties_changed: bool,
+    },
+    UnsubscribeObjectManager {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(flatten)]
+        key: OwnedObjectKey,
+    },
+    ListSubscriptions {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+    },
+    Monitor {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(default)]
+        rules: Vec<OwnedMatchRule>,
+    },
+    StopMonitor {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        subscription_id: SubscriptionId,
+    },
+    ExportObject {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        path: OwnedObjectPath,
+        interfaces: Vec<InterfaceDefinition>,
+        #[serde(default)]
+        properties: ExportedProperties,
+    },
+    UnexportObject {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        path: OwnedObjectPath,
+    },
+    SetExportedProperty {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+        name: OwnedPropertyName,
+        value: serde_json::Value,
+    },
+    ReturnMethodCall {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        call_id: CallId,
+        #[serde(default)]
+        args: Vec<serde_json::Value>,
+    },
+    ReturnMethodError {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        call_id: CallId,
+        error_name: OwnedErrorName,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    EmitSignal {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        #[serde(default)]
+        destination: Option<OwnedBusName>,
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+        name: OwnedMemberName,
+        #[serde(default)]
+        args: Vec<Value>,
+    },
+    RequestName {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        name: OwnedWellKnownName,
+        #[serde(flatten)]
+        flags: NameFlags,
     },
+    ReleaseName {
+        #[serde(default)]
+        request_id: Option<RequestId>,
+        #[serde(default)]
+        connection: Option<DBusConnectionTarget>,
+        name: OwnedWellKnownName,
+    },
+    CancelRequest {
+        request_id: RequestId,
+    },
+}
+
+/// Header fields of the received message.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageHeader {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender: Option<String>,
+    serial: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_serial: Option<u32>,
+    signature: zvariant::Signature,
+    /// Milliseconds since the Unix epoch when the proxy reads the message from the connection,
+    /// the signals delayed by the delivery options keep the time they were read
+    timestamp: u64,
+}
+
+impl From<&zbus::Message> for MessageHeader {
+    fn from(msg: &zbus::Message) -> Self {
+        let header = msg.header();
+        Self {
+            sender: header.sender().map(ToString::to_string),
+            serial: header.primary().serial_num().get(),
+            reply_serial: header.reply_serial().map(|serial| serial.get()),
+            signature: msg.body().signature().clone(),
+            timestamp: timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum MessageType {
+    MethodCall,
+    MethodReturn,
+    Error,
+    Signal,
+}
+
+impl From<Type> for MessageType {
+    fn from(value: Type) -> Self {
+        match value {
+            Type::MethodCall => MessageType::MethodCall,
+            Type::MethodReturn => MessageType::MethodReturn,
+            Type::Error => MessageType::Error,
+            Type::Signal => MessageType::Signal,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |timestamp| timestamp.as_millis() as u64)
+}
+
+/// Property values by name for each interface name.
+pub type InterfacesProperties = HashMap<String, HashMap<String, Value>>;
+
+/// Any message received by the monitor connection.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MonitoredMessage {
+    pub subscriptions: Vec<SubscriptionId>,
+    message_type: MessageType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<OwnedBusName>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<OwnedObjectPath>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interface: Option<OwnedInterfaceName>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    member: Option<OwnedMemberName>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_name: Option<String>,
+    header: MessageHeader,
+    args: Vec<Value>,
 }
 
 #[derive(Debug, Serialize)]
@@ -68,17 +434,115 @@ pub enum InputMessage<'a> {
 pub enum OutputMessage {
     MethodReturn {
         request_id: Option<RequestId>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        header: Option<MessageHeader>,
         args: Vec<Value>,
     },
     MethodError {
         request_id: Option<RequestId>,
+        error_name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
         args: Vec<Value>,
     },
+    Subscription {
+        request_id: Option<RequestId>,
+        subscription_id: SubscriptionId,
+    },
     Signal {
-        #[serde(flatten)]
-        key: OwnedSignalKey,
+        subscriptions: Vec<SubscriptionId>,
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+        name: OwnedMemberName,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        header: Option<MessageHeader>,
+        args: Vec<Value>,
+    },
+    ServiceVanished {
+        subscriptions: Vec<SubscriptionId>,
+        name: OwnedWellKnownName,
+    },
+    ServiceAppeared {
+        subscriptions: Vec<SubscriptionId>,
+        name: OwnedWellKnownName,
+        owner: OwnedUniqueName,
+    },
+    Monitored(Box<MonitoredMessage>),
+    IncomingMethodCall {
+        call_id: CallId,
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+        method_name: OwnedMemberName,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        no_reply_expected: bool,
+        header: MessageHeader,
         args: Vec<Value>,
     },
+    ExportedPropertySet {
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+        name: OwnedPropertyName,
+        value: Value,
+    },
+    NameRequested {
+        request_id: Option<RequestId>,
+        name: OwnedWellKnownName,
+        reply: RequestNameReply,
+    },
+    NameReleased {
+        request_id: Option<RequestId>,
+        name: OwnedWellKnownName,
+        reply: ReleaseNameReply,
+    },
+    NameAcquired {
+        name: OwnedWellKnownName,
+    },
+    NameLost {
+        name: OwnedWellKnownName,
+    },
+    Introspection {
+        request_id: Option<RequestId>,
+        object: ObjectDescription,
+    },
+    Property {
+        request_id: Option<RequestId>,
+        value: Value,
+    },
+    Properties {
+        request_id: Option<RequestId>,
+        properties: HashMap<String, Value>,
+    },
+    PropertiesChanged {
+        #[serde(flatten)]
+        key: OwnedInterfaceKey,
+        changed: HashMap<String, Value>,
+        invalidated: Vec<String>,
+    },
+    ManagedObjects {
+        request_id: Option<RequestId>,
+        #[serde(flatten)]
+        key: OwnedObjectKey,
+        objects: HashMap<OwnedObjectPath, InterfacesProperties>,
+    },
+    InterfacesAdded {
+        #[serde(flatten)]
+        key: OwnedObjectKey,
+        object: OwnedObjectPath,
+        interfaces: InterfacesProperties,
+    },
+    InterfacesRemoved {
+        #[serde(flatten)]
+        key: OwnedObjectKey,
+        object: OwnedObjectPath,
+        interfaces: Vec<String>,
+    },
+    Subscriptions {
+        request_id: Option<RequestId>,
+        subscriptions: Vec<SubscriptionInfo>,
+    },
+    SignalsDropped {
+        count: u64,
+    },
     Success {
         request_id: Option<RequestId>,
     },
@@ -89,20 +553,316 @@ pub enum OutputMessage {
     },
 }
 
-impl<'a> InputMessage<'a> {
+impl InputMessage {
     pub fn request_id(&self) -> Option<RequestId> {
         match self {
-            InputMessage::CallMethod { request_id, .. }
+            InputMessage::CallMethod(MethodCall { request_id, .. })
             | InputMessage::SubscribeSignal { request_id, .. }
-            | InputMessage::UnsubscribeSignal { request_id, .. } => *request_id,
+            | InputMessage::UnsubscribeSignal { request_id, .. }
+            | InputMessage::SubscribeMatchRule { request_id, .. }
+            | InputMessage::UnsubscribeMatchRule { request_id, .. }
+            | InputMessage::Introspect { request_id, .. }
+            | InputMessage::GetProperty { request_id, .. }
+            | InputMessage::SetProperty { request_id, .. }
+            | InputMessage::GetAllProperties { request_id, .. }
+            | InputMessage::SubscribePropertyChanges { request_id, .. }
+            | InputMessage::UnsubscribePropertyChanges { request_id, .. }
+            | InputMessage::SubscribeObjectManager { request_id, .. }
+            | InputMessage::UnsubscribeObjectManager { request_id, .. }
+            | InputMessage::ListSubscriptions { request_id, .. }
+            | InputMessage::Monitor { request_id, .. }
+            | InputMessage::StopMonitor { request_id, .. }
+            | InputMessage::ExportObject { request_id, .. }
+            | InputMessage::UnexportObject { request_id, .. }
+            | InputMessage::SetExportedProperty { request_id, .. }
+            | InputMessage::ReturnMethodCall { request_id, .. }
+            | InputMessage::ReturnMethodError { request_id, .. }
+            | InputMessage::EmitSignal { request_id, .. }
+            | InputMessage::RequestName { request_id, .. }
+            | InputMessage::ReleaseName { request_id, .. } => *request_id,
+            InputMessage::CancelRequest { request_id } => Some(*request_id),
+        }
+    }
+
+    /// DBus connection the message is sent to, the default connection of the WebSocket is used if it is not set.
+    pub fn connection(&self) -> Option<DBusConnectionTarget> {
+        match self {
+            InputMessage::CallMethod(MethodCall { connection, .. })
+            | InputMessage::SubscribeSignal { connection, .. }
+            | InputMessage::UnsubscribeSignal { connection, .. }
+            | InputMessage::SubscribeMatchRule { connection, .. }
+            | InputMessage::UnsubscribeMatchRule { connection, .. }
+            | InputMessage::Introspect { connection, .. }
+            | InputMessage::GetProperty { connection, .. }
+            | InputMessage::SetProperty { connection, .. }
+            | InputMessage::GetAllProperties { connection, .. }
+            | InputMessage::SubscribePropertyChanges { connection, .. }
+            | InputMessage::UnsubscribePropertyChanges { connection, .. }
+            | InputMessage::SubscribeObjectManager { connection, .. }
+            | InputMessage::UnsubscribeObjectManager { connection, .. }
+            | InputMessage::ListSubscriptions { connection, .. }
+            | InputMessage::Monitor { connection, .. }
+            | InputMessage::StopMonitor { connection, .. }
+            | InputMessage::ExportObject { connection, .. }
+            | InputMessage::UnexportObject { connection, .. }
+            | InputMessage::SetExportedProperty { connection, .. }
+            | InputMessage::ReturnMethodCall { connection, .. }
+            | InputMessage::ReturnMethodError { connection, .. }
+            | InputMessage::EmitSignal { connection, .. }
+            | InputMessage::RequestName { connection, .. }
+            | InputMessage::ReleaseName { connection, .. } => *connection,
+            InputMessage::CancelRequest { .. } => None,
+        }
+    }
+
+    /// The requests changing the subscriptions, names or exported objects are processed in the receive order,
+    /// the calls are processed concurrently.
+    pub fn is_ordered(&self) -> bool {
+        !matches!(
+            self,
+            InputMessage::CallMethod(_)
+                | InputMessage::Introspect { .. }
+                | InputMessage::GetProperty { .. }
+                | InputMessage::SetProperty { .. }
+                | InputMessage::GetAllProperties { .. }
+        )
+    }
+}
+
+/// Output message with the DBus connection it is received from, that is set in the message body.
+/// The messages of the proxy itself, like the input format errors, have no connection.
+#[derive(Debug)]
+pub struct TaggedOutputMessage {
+    pub connection: Option<DBusConnectionTarget>,
+    pub message: OutputMessage,
+}
+
+impl TaggedOutputMessage {
+    pub fn new(connection: Option<DBusConnectionTarget>, message: OutputMessage) -> Self {
+        Self {
+            connection,
+            message,
         }
     }
 }
 
+impl From<OutputMessage> for TaggedOutputMessage {
+    fn from(message: OutputMessage) -> Self {
+        Self::new(None, message)
+    }
+}
+
+impl Serialize for TaggedOutputMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.connection {
+            Some(connection) => self.message.serialize(BodySerializer {
+                serializer,
+                connection,
+            }),
+            None => self.message.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WithConnection<'a, T: ?Sized> {
+    connection: DBusConnectionTarget,
+    #[serde(flatten)]
+    body: &'a T,
+}
+
+// Serializes the output message variant with the connection added to its body
+struct BodySerializer<S> {
+    serializer: S,
+    connection: DBusConnectionTarget,
+}
+
+impl<S: Serializer> BodySerializer<S> {
+    fn unsupported(&self) -> S::Error {
+        S::Error::custom("output message body must be a struct")
+    }
+}
+
+macro_rules! unsupported {
+    ($($method:ident($($arg:ty),*);)*) => {
+        $(fn $method(self, $(_: $arg),*) -> Result<S::Ok, S::Error> {
+            Err(self.unsupported())
+        })*
+    };
+}
+
+impl<S: Serializer> Serializer for BodySerializer<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Impossible<S::Ok, S::Error>;
+    type SerializeTuple = Impossible<S::Ok, S::Error>;
+    type SerializeTupleStruct = Impossible<S::Ok, S::Error>;
+    type SerializeTupleVariant = Impossible<S::Ok, S::Error>;
+    type SerializeMap = Impossible<S::Ok, S::Error>;
+    type SerializeStruct = Impossible<S::Ok, S::Error>;
+    type SerializeStructVariant = S::SerializeStructVariant;
+
+    unsupported! {
+        serialize_bool(bool);
+        serialize_i8(i8);
+        serialize_i16(i16);
+        serialize_i32(i32);
+        serialize_i64(i64);
+        serialize_u8(u8);
+        serialize_u16(u16);
+        serialize_u32(u32);
+        serialize_u64(u64);
+        serialize_f32(f32);
+        serialize_f64(f64);
+        serialize_char(char);
+        serialize_str(&str);
+        serialize_bytes(&[u8]);
+        serialize_none();
+        serialize_unit();
+        serialize_unit_struct(&'static str);
+        serialize_unit_variant(&'static str, u32, &'static str);
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<S::Ok, S::Error> {
+        Err(self.unsupported())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<S::Ok, S::Error> {
+        Err(self.unsupported())
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        let mut message = self.serializer.serialize_map(Some(1))?;
+        message.serialize_entry(
+            variant,
+            &WithConnection {
+                connection: self.connection,
+                body: value,
+            },
+        )?;
+        message.end()
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, S::Error> {
+        let mut body =
+            self.serializer
+                .serialize_struct_variant(name, variant_index, variant, len + 1)?;
+        body.serialize_field("connection", &self.connection)?;
+        Ok(body)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+        Err(self.unsupported())
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, S::Error> {
+        Err(self.unsupported())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, S::Error> {
+        Err(self.unsupported())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, S::Error> {
+        Err(self.unsupported())
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+        Err(self.unsupported())
+    }
+
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStruct, S::Error> {
+        Err(self.unsupported())
+    }
+}
+
 impl OutputMessage {
+    /// Subscription events can be dropped if the client is too slow.
+    pub fn is_event(&self) -> bool {
+        matches!(
+            self,
+            OutputMessage::Signal { .. }
+                | OutputMessage::ServiceVanished { .. }
+                | OutputMessage::ServiceAppeared { .. }
+                | OutputMessage::PropertiesChanged { .. }
+                | OutputMessage::InterfacesAdded { .. }
+                | OutputMessage::InterfacesRemoved { .. }
+                | OutputMessage::Monitored(_)
+        )
+    }
+
+    /// The subscriptions are set on delivery, the header is removed if it is not requested by any of them.
+    pub fn from_signal(msg: &zbus::Message) -> crate::Result<Self> {
+        let msg_header = msg.header();
+        let (Some(path), Some(interface), Some(name)) = (
+            msg_header.path(),
+            msg_header.interface(),
+            msg_header.member(),
+        ) else {
+            return Err(zbus::Error::MissingField.into());
+        };
+        Ok(OutputMessage::Signal {
+            subscriptions: Vec::new(),
+            path: path.to_owned().into(),
+            interface: interface.to_owned().into(),
+            name: name.to_owned().into(),
+            header: Some(msg.into()),
+            args: Value::try_to_array_from_body(&msg.body())?,
+        })
+    }
+
+    /// Any message received by the monitor connection, the subscriptions are set on delivery.
+    pub fn from_monitored(msg: &zbus::Message) -> crate::Result<Self> {
+        let header = msg.header();
+        Ok(OutputMessage::Monitored(Box::new(MonitoredMessage {
+            subscriptions: Vec::new(),
+            message_type: msg.message_type().into(),
+            destination: header.destination().map(|name| name.to_owned().into()),
+            path: header.path().map(|path| path.to_owned().into()),
+            interface: header
+                .interface()
+                .map(|interface| interface.to_owned().into()),
+            member: header.member().map(|member| member.to_owned().into()),
+            error_name: header.error_name().map(ToString::to_string),
+            header: msg.into(),
+            args: Value::try_to_array_from_body(&msg.body())?,
+        })))
+    }
+
     pub fn from_method_call_result(
         msg: zbus::Message,
         request_id: Option<RequestId>,
+        include_header: bool,
     ) -> RequestResult<Self> {
         let args = Value::try_to_array_from_body(&msg.body())
             .map_err(|err| RequestError::new(request_id, err))?;
@@ -113,8 +873,12 @@ impl OutputMessage {
                     "Method call message cannot be converted to Output message".into(),
                 ),
             )),
-            Type::MethodReturn => Ok(OutputMessage::MethodReturn { request_id, args }),
-            Type::Error => Ok(OutputMessage::MethodError { request_id, args }),
+            Type::MethodReturn => Ok(OutputMessage::MethodReturn {
+                request_id,
+                header: include_header.then(|| (&msg).into()),
+                args,
+            }),
+            Type::Error => Ok(RequestError::new(request_id, zbus::Error::from(msg)).into()),
             Type::Signal => Err(RequestError::new(
                 request_id,
                 Error::UnsupportedFormat("Signal cannot be converted to Output message".into()),
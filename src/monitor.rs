@@ -0,0 +1,32 @@
+use crate::message::OutputMessage;
+use crate::state::SubscriptionStream;
+use crate::{dbus_connection, WebSocketParameters};
+use tokio_stream::StreamExt;
+use tracing::{info, instrument};
+use zbus::fdo::MonitoringProxy;
+use zbus::{MatchRule, MessageStream, OwnedMatchRule};
+
+/// Stream of all messages of the bus matching any of the rules, all messages are matched if there are no rules.
+///
+/// The monitor cannot send messages, so the separate connection is opened for each monitor stream
+/// and closed with it.
+#[instrument]
+pub async fn monitor(
+    params: &WebSocketParameters,
+    rules: &[OwnedMatchRule],
+) -> crate::Result<SubscriptionStream> {
+    let connection = dbus_connection(params).await?;
+    let rules: Vec<_> = rules.iter().map(MatchRule::from).collect();
+    MonitoringProxy::new(&connection)
+        .await?
+        .become_monitor(&rules, 0)
+        .await
+        .map_err(zbus::Error::from)?;
+    info!("Connection {:?} became monitor", connection.unique_name());
+    Ok(Box::pin(MessageStream::from(connection).map(
+        |msg| match msg {
+            Ok(msg) => OutputMessage::from_monitored(&msg),
+            Err(err) => Err(err.into()),
+        },
+    )))
+}
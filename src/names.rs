@@ -0,0 +1,237 @@
+use crate::message::OutputMessage;
+use crate::state::SubscriptionStream;
+use enumflags2::BitFlags;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tracing::{instrument, warn};
+use zbus::fdo::{self, DBusProxy, RequestNameFlags};
+use zbus::names::{BusName, OwnedWellKnownName, WellKnownName};
+
+/// Flags of the name request, the name is queued if it is owned by another connection
+/// unless `doNotQueue` is set.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NameFlags {
+    pub allow_replacement: bool,
+    pub replace_existing: bool,
+    pub do_not_queue: bool,
+}
+
+impl NameFlags {
+    pub fn request_flags(&self) -> BitFlags<RequestNameFlags> {
+        [
+            (self.allow_replacement, RequestNameFlags::AllowReplacement),
+            (self.replace_existing, RequestNameFlags::ReplaceExisting),
+            (self.do_not_queue, RequestNameFlags::DoNotQueue),
+        ]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum RequestNameReply {
+    PrimaryOwner,
+    InQueue,
+    Exists,
+    AlreadyOwner,
+}
+
+impl From<fdo::RequestNameReply> for RequestNameReply {
+    fn from(reply: fdo::RequestNameReply) -> Self {
+        match reply {
+            fdo::RequestNameReply::PrimaryOwner => RequestNameReply::PrimaryOwner,
+            fdo::RequestNameReply::InQueue => RequestNameReply::InQueue,
+            fdo::RequestNameReply::Exists => RequestNameReply::Exists,
+            fdo::RequestNameReply::AlreadyOwner => RequestNameReply::AlreadyOwner,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ReleaseNameReply {
+    Released,
+    NonExistent,
+    NotOwner,
+}
+
+impl From<fdo::ReleaseNameReply> for ReleaseNameReply {
+    fn from(reply: fdo::ReleaseNameReply) -> Self {
+        match reply {
+            fdo::ReleaseNameReply::Released => ReleaseNameReply::Released,
+            fdo::ReleaseNameReply::NonExistent => ReleaseNameReply::NonExistent,
+            fdo::ReleaseNameReply::NotOwner => ReleaseNameReply::NotOwner,
+        }
+    }
+}
+
+/// Well-known names requested by the client, they are released when the WebSocket is closed.
+#[derive(Debug, Default)]
+pub struct OwnedNames {
+    names: Mutex<TrackedNames>,
+    /// Locked while the stream is created, so the concurrent requests wait for it
+    watching: tokio::sync::Mutex<bool>,
+}
+
+#[derive(Debug, Default)]
+struct TrackedNames {
+    requested: HashSet<OwnedWellKnownName>,
+    /// Names with the requests waiting for the reply, by the number of the requests
+    pending: HashMap<OwnedWellKnownName, usize>,
+}
+
+impl OwnedNames {
+    /// The name is tracked even if it is queued, so the later acquisition is reported.
+    /// The bus sends `NameAcquired` before the reply, so the name is tracked during the request.
+    #[instrument]
+    pub async fn request(
+        &self,
+        connection: &zbus::Connection,
+        name: OwnedWellKnownName,
+        flags: &NameFlags,
+    ) -> crate::Result<RequestNameReply> {
+        *self
+            .names
+            .lock()
+            .unwrap()
+            .pending
+            .entry(name.clone())
+            .or_default() += 1;
+        let reply = async {
+            DBusProxy::new(connection)
+                .await?
+                .request_name(name.as_ref(), flags.request_flags())
+                .await
+                .map_err(zbus::Error::from)
+        }
+        .await;
+        let mut names = self.names.lock().unwrap();
+        if let Some(pending) = names.pending.get_mut(&name) {
+            *pending -= 1;
+            if *pending == 0 {
+                names.pending.remove(&name);
+            }
+        }
+        if matches!(reply, Ok(ref reply) if *reply != fdo::RequestNameReply::Exists) {
+            names.requested.insert(name);
+        }
+        Ok(reply?.into())
+    }
+
+    #[instrument]
+    pub async fn release(
+        &self,
+        connection: &zbus::Connection,
+        name: OwnedWellKnownName,
+    ) -> crate::Result<ReleaseNameReply> {
+        let reply = DBusProxy::new(connection)
+            .await?
+            .release_name(name.as_ref())
+            .await
+            .map_err(zbus::Error::from)?;
+        self.names.lock().unwrap().requested.remove(&name);
+        Ok(reply.into())
+    }
+
+    /// Releases all requested names, the errors are only logged.
+    /// The names of the aborted requests are released too, they can be already acquired.
+    pub async fn release_all(&self, connection: &zbus::Connection) {
+        let names: Vec<_> = {
+            let mut names = self.names.lock().unwrap();
+            let pending = names
+                .pending
+                .drain()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>();
+            names.requested.extend(pending);
+            names.requested.drain().collect()
+        };
+        if names.is_empty() {
+            return;
+        }
+        let proxy = match DBusProxy::new(connection).await {
+            Ok(proxy) => proxy,
+            Err(err) => {
+                warn!("Cannot release the requested names: {}", err);
+                return;
+            }
+        };
+        for name in names {
+            if let Err(err) = proxy.release_name(name.as_ref()).await {
+                warn!("Cannot release the name '{}': {}", name, err);
+            }
+        }
+    }
+
+    fn contains(&self, name: &WellKnownName) -> bool {
+        let names = self.names.lock().unwrap();
+        names.requested.contains(name.as_str()) || names.pending.contains_key(name.as_str())
+    }
+}
+
+/// Stream of the `NameAcquired` and `NameLost` messages of the requested names.
+/// The stream is created once for the WebSocket, `None` is returned if it already exists.
+pub async fn watch(
+    names: &Arc<OwnedNames>,
+    connection: &zbus::Connection,
+) -> crate::Result<Option<SubscriptionStream>> {
+    let mut watching = names.watching.lock().await;
+    if *watching {
+        return Ok(None);
+    }
+    // The caller requests the name only after this returns, so its NameAcquired is received
+    let proxy = DBusProxy::new(connection).await?;
+    let acquired = proxy.receive_name_acquired().await?;
+    let lost = proxy.receive_name_lost().await?;
+    *watching = true;
+    let acquired = acquired.map(|signal| {
+        let name = signal.args().map(|args| args.name.into_owned())?;
+        Ok((name, true))
+    });
+    let lost = lost.map(|signal| {
+        let name = signal.args().map(|args| args.name.into_owned())?;
+        Ok((name, false))
+    });
+    let names = names.clone();
+    Ok(Some(Box::pin(
+        futures_util::stream::select(acquired, lost).filter_map(
+            move |result: zbus::Result<(BusName<'static>, bool)>| {
+                let message = match result {
+                    // The unique name of the connection is acquired too
+                    Ok((BusName::WellKnown(name), acquired)) if names.contains(&name) => {
+                        let name = name.into();
+                        Some(Ok(if acquired {
+                            OutputMessage::NameAcquired { name }
+                        } else {
+                            OutputMessage::NameLost { name }
+                        }))
+                    }
+                    Ok(_) => None,
+                    Err(err) => Some(Err(err.into())),
+                };
+                async move { message }
+            },
+        ),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_converted() {
+        let flags: NameFlags =
+            serde_json::from_str(r#"{"allowReplacement":true,"doNotQueue":true}"#).unwrap();
+        assert_eq!(
+            flags.request_flags(),
+            RequestNameFlags::AllowReplacement | RequestNameFlags::DoNotQueue
+        );
+        assert!(NameFlags::default().request_flags().is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::interfaces;
+use crate::message::{InterfacesProperties, OutputMessage, OwnedObjectKey, RequestId};
+use crate::properties;
+use crate::state::SubscriptionStream;
+use std::collections::HashMap;
+use tokio_stream::StreamExt;
+use tracing::instrument;
+use zbus::message::Type;
+use zbus::{MatchRule, MessageStream};
+use zvariant::{OwnedObjectPath, OwnedValue};
+
+type OwnedInterfacesProperties = HashMap<String, HashMap<String, OwnedValue>>;
+
+/// Stream of the managed objects snapshot followed by the `InterfacesAdded` and `InterfacesRemoved` deltas.
+/// The `PropertiesChanged` signals of the managed objects are included if requested.
+#[instrument(skip(connection))]
+pub async fn changes(
+    connection: &zbus::Connection,
+    request_id: Option<RequestId>,
+    key: OwnedObjectKey,
+    properties_changed: bool,
+) -> crate::Result<SubscriptionStream> {
+    // The deltas queue up while GetManagedObjects runs and follow the snapshot
+    let mut rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .path(key.path.clone())?
+        .interface(interfaces::OBJECT_MANAGER)?;
+    if let Some(destination) = &key.destination {
+        rule = rule.sender(destination.clone())?;
+    }
+    let deltas = MessageStream::for_match_rule(rule.build(), connection, None).await?;
+    let deltas: SubscriptionStream = {
+        let key = key.clone();
+        Box::pin(deltas.map(move |msg| interfaces_changed(key.clone(), &msg?)))
+    };
+    let deltas = if properties_changed {
+        let mut rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .path_namespace(key.path.clone())?
+            .interface(interfaces::PROPERTIES)?
+            .member("PropertiesChanged")?;
+        if let Some(destination) = &key.destination {
+            rule = rule.sender(destination.clone())?;
+        }
+        let changes = MessageStream::for_match_rule(rule.build(), connection, None).await?;
+        let connection = connection.clone();
+        let destination = key.destination.clone();
+        let changes = changes.then(move |msg| {
+            let connection = connection.clone();
+            let destination = destination.clone();
+            async move { properties::properties_changed(&connection, destination, msg?).await }
+        });
+        Box::pin(deltas.merge(changes))
+    } else {
+        deltas
+    };
+    let reply = connection
+        .call_method(
+            key.destination.as_ref(),
+            &key.path,
+            Some(interfaces::OBJECT_MANAGER),
+            "GetManagedObjects",
+            &(),
+        )
+        .await?;
+    let objects: HashMap<OwnedObjectPath, OwnedInterfacesProperties> =
+        reply.body().deserialize()?;
+    let snapshot = OutputMessage::ManagedObjects {
+        request_id,
+        key,
+        objects: objects
+            .into_iter()
+            .map(|(path, interfaces)| (path, decode(interfaces)))
+            .collect(),
+    };
+    Ok(Box::pin(tokio_stream::once(Ok(snapshot)).chain(deltas)))
+}
+
+fn interfaces_changed(key: OwnedObjectKey, msg: &zbus::Message) -> crate::Result<OutputMessage> {
+    let header = msg.header();
+    match header.member().map(|member| member.as_str()) {
+        Some("InterfacesAdded") => {
+            let (object, interfaces): (OwnedObjectPath, OwnedInterfacesProperties) =
+                msg.body().deserialize()?;
+            Ok(OutputMessage::InterfacesAdded {
+                key,
+                object,
+                interfaces: decode(interfaces),
+            })
+        }
+        Some("InterfacesRemoved") => {
+            let (object, interfaces): (OwnedObjectPath, Vec<String>) = msg.body().deserialize()?;
+            Ok(OutputMessage::InterfacesRemoved {
+                key,
+                object,
+                interfaces,
+            })
+        }
+        _ => Err(zbus::Error::InvalidField.into()),
+    }
+}
+
+fn decode(interfaces: OwnedInterfacesProperties) -> InterfacesProperties {
+    interfaces
+        .into_iter()
+        .map(|(interface, properties)| {
+            let properties = properties
+                .into_iter()
+                .map(|(name, value)| (name, value.into()))
+                .collect();
+            (interface, properties)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> OwnedObjectKey {
+        OwnedObjectKey {
+            destination: None,
+            path: OwnedObjectPath::try_from("/org/example").unwrap(),
+        }
+    }
+
+    #[test]
+    fn interfaces_added() {
+        let properties = HashMap::from([("Connected", zvariant::Value::from(true))]);
+        let msg = zbus::Message::signal(
+            "/org/example",
+            interfaces::OBJECT_MANAGER,
+            "InterfacesAdded",
+        )
+        .unwrap()
+        .build(&(
+            zvariant::ObjectPath::try_from("/org/example/dev0").unwrap(),
+            HashMap::from([("org.example.Device", properties)]),
+        ))
+        .unwrap();
+        let Ok(OutputMessage::InterfacesAdded {
+            object, interfaces, ..
+        }) = interfaces_changed(key(), &msg)
+        else {
+            panic!("Unexpected message");
+        };
+        assert_eq!(object.as_str(), "/org/example/dev0");
+        assert_eq!(
+            serde_json::to_value(&interfaces).unwrap(),
+            serde_json::json!({
+                "org.example.Device": {"Connected": {"type": "bool", "value": true}}
+            })
+        );
+    }
+
+    #[test]
+    fn interfaces_removed() {
+        let msg = zbus::Message::signal(
+            "/org/example",
+            interfaces::OBJECT_MANAGER,
+            "InterfacesRemoved",
+        )
+        .unwrap()
+        .build(&(
+            zvariant::ObjectPath::try_from("/org/example/dev0").unwrap(),
+            vec!["org.example.Device"],
+        ))
+        .unwrap();
+        let Ok(OutputMessage::InterfacesRemoved {
+            object, interfaces, ..
+        }) = interfaces_changed(key(), &msg)
+        else {
+            panic!("Unexpected message");
+        };
+        assert_eq!(object.as_str(), "/org/example/dev0");
+        assert_eq!(interfaces, vec!["org.example.Device"]);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::message::{OutputMessage, TaggedOutputMessage};
+use axum::extract::ws::{close_code, CloseFrame};
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// Action when the outbound queue is full of the subscription events.
+#[derive(ValueEnum, Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Close,
+}
+
+#[derive(Debug)]
+pub enum Outbound {
+    Message(TaggedOutputMessage),
+    Close(CloseFrame<'static>),
+}
+
+// Messages waiting to be sent to the WebSocket. The capacity limits only the subscription events,
+// the replies to the requests are never dropped.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    inner: Mutex<OutboundInner>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+#[derive(Debug, Default)]
+struct OutboundInner {
+    messages: VecDeque<TaggedOutputMessage>,
+    events: usize,
+    dropped: u64,
+    close: Option<CloseFrame<'static>>,
+    closed: bool,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            inner: Mutex::default(),
+            notify: Notify::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    pub fn push(&self, msg: impl Into<TaggedOutputMessage>) {
+        let msg = msg.into();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            return;
+        }
+        if msg.message.is_event() {
+            if inner.events >= self.capacity {
+                match self.policy {
+                    OverflowPolicy::DropOldest => {
+                        inner.dropped += 1;
+                        let Some(index) =
+                            inner.messages.iter().position(|msg| msg.message.is_event())
+                        else {
+                            return;
+                        };
+                        inner.messages.remove(index);
+                        inner.events -= 1;
+                    }
+                    OverflowPolicy::DropNewest => {
+                        inner.dropped += 1;
+                        return;
+                    }
+                    OverflowPolicy::Close => {
+                        warn!("Outbound queue overflow, the WebSocket is closed");
+                        inner.close = Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "Outbound queue overflow".into(),
+                        });
+                        inner.closed = true;
+                        self.notify.notify_waiters();
+                        return;
+                    }
+                }
+            }
+            inner.events += 1;
+        }
+        inner.messages.push_back(msg);
+        self.notify.notify_waiters();
+    }
+
+    /// The queued messages are still sent after the queue is closed.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.notify.notify_waiters();
+    }
+
+    /// Completes when the queue is closed because of the overflow.
+    pub async fn overflowed(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.inner.lock().unwrap().close.is_some() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// The next message to send, the lost events are reported before it.
+    pub async fn next(&self) -> Option<Outbound> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.close.is_some() {
+                    // The queued replies are sent before the close frame, the events are dropped
+                    inner.messages.retain(|msg| !msg.message.is_event());
+                    inner.events = 0;
+                    return match inner.messages.pop_front() {
+                        Some(msg) => Some(Outbound::Message(msg)),
+                        None => inner.close.take().map(Outbound::Close),
+                    };
+                }
+                if inner.dropped > 0 {
+                    let count = std::mem::take(&mut inner.dropped);
+                    let msg = OutputMessage::SignalsDropped { count };
+                    return Some(Outbound::Message(msg.into()));
+                }
+                if let Some(msg) = inner.messages.pop_front() {
+                    if msg.message.is_event() {
+                        inner.events -= 1;
+                    }
+                    return Some(Outbound::Message(msg));
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DBusConnectionTarget;
+
+    fn event(value: u32) -> OutputMessage {
+        OutputMessage::ServiceAppeared {
+            subscriptions: vec![value as u64],
+            name: "org.example.Test".try_into().unwrap(),
+            owner: ":1.1".try_into().unwrap(),
+        }
+    }
+
+    fn reply(request_id: u64) -> OutputMessage {
+        OutputMessage::Success {
+            request_id: Some(request_id),
+        }
+    }
+
+    async fn drain(queue: &OutboundQueue) -> Vec<String> {
+        queue.close();
+        let mut sent = Vec::new();
+        while let Some(outbound) = queue.next().await {
+            let Outbound::Message(msg) = outbound else {
+                sent.push("Close".to_string());
+                continue;
+            };
+            sent.push(serde_json::to_string(&msg).unwrap());
+        }
+        sent
+    }
+
+    #[tokio::test]
+    async fn oldest_events_are_dropped() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::DropOldest);
+        queue.push(event(1));
+        queue.push(reply(1));
+        queue.push(event(2));
+        queue.push(event(3));
+        queue.push(reply(2));
+        let sent = drain(&queue).await;
+        assert_eq!(sent.len(), 5);
+        assert_eq!(sent[0], r#"{"SignalsDropped":{"count":1}}"#);
+        assert_eq!(sent[1], r#"{"Success":{"requestId":1}}"#);
+        assert!(sent[2].contains("[2]"));
+        assert!(sent[3].contains("[3]"));
+        assert_eq!(sent[4], r#"{"Success":{"requestId":2}}"#);
+    }
+
+    #[tokio::test]
+    async fn newest_events_are_dropped() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::DropNewest);
+        queue.push(event(1));
+        queue.push(event(2));
+        queue.push(event(3));
+        queue.push(reply(1));
+        let sent = drain(&queue).await;
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0], r#"{"SignalsDropped":{"count":2}}"#);
+        assert!(sent[1].contains("[1]"));
+        assert_eq!(sent[2], r#"{"Success":{"requestId":1}}"#);
+    }
+
+    #[tokio::test]
+    async fn connection_is_set_in_message_body() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::DropOldest);
+        queue.push(TaggedOutputMessage::new(
+            Some(DBusConnectionTarget::System),
+            reply(1),
+        ));
+        queue.push(reply(2));
+        let sent = drain(&queue).await;
+        assert_eq!(
+            sent[0],
+            r#"{"Success":{"connection":"system","requestId":1}}"#
+        );
+        assert_eq!(sent[1], r#"{"Success":{"requestId":2}}"#);
+    }
+
+    #[tokio::test]
+    async fn connection_is_set_in_monitored_message_body() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::DropOldest);
+        let msg = zbus::Message::signal("/org/example", "org.example.Test", "Tick")
+            .unwrap()
+            .build(&())
+            .unwrap();
+        queue.push(TaggedOutputMessage::new(
+            Some(DBusConnectionTarget::Session),
+            OutputMessage::from_monitored(&msg).unwrap(),
+        ));
+        let sent: serde_json::Value = serde_json::from_str(&drain(&queue).await[0]).unwrap();
+        assert_eq!(sent["Monitored"]["connection"], "session");
+        assert_eq!(sent["Monitored"]["member"], "Tick");
+    }
+
+    #[tokio::test]
+    async fn overflow_closes_queue() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::Close);
+        queue.push(event(1));
+        queue.push(reply(1));
+        queue.push(event(2));
+        queue.overflowed().await;
+        queue.push(reply(2));
+        assert_eq!(
+            drain(&queue).await,
+            vec![r#"{"Success":{"requestId":1}}"#, "Close"]
+        );
+    }
+}
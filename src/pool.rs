@@ -0,0 +1,215 @@
+use crate::{dbus_connection, DBusConnectionTarget, WebSocketParameters};
+use futures_util::{FutureExt, StreamExt};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tracing::{info, instrument, warn};
+use zbus::message::Type;
+use zbus::{MatchRule, MessageStream};
+
+/// DBus connections shared by the WebSockets with the same connection parameters.
+/// The connection is closed when the last WebSocket using it is closed.
+#[derive(Debug, Default)]
+pub struct ConnectionPool(Mutex<PoolState>);
+
+#[derive(Debug, Default)]
+struct PoolState {
+    last_id: u64,
+    connections: HashMap<PoolKey, PooledConnection>,
+}
+
+#[derive(Debug, Eq, PartialEq, Hash)]
+struct PoolKey {
+    target: DBusConnectionTarget,
+    address: Option<String>,
+    peer_to_peer: bool,
+}
+
+#[derive(Debug)]
+struct PooledConnection {
+    id: u64,
+    connection: zbus::Connection,
+    // Receives only the error of the broken connection, the stream ends after it
+    failure: MessageStream,
+    sessions: usize,
+}
+
+/// Connection of the WebSocket, the shared connection has the id of its pool entry.
+#[derive(Debug)]
+pub struct Lease {
+    pub connection: zbus::Connection,
+    pub id: Option<u64>,
+}
+
+impl From<&WebSocketParameters> for PoolKey {
+    fn from(params: &WebSocketParameters) -> Self {
+        Self {
+            target: params.target(),
+            address: params.address.clone(),
+            peer_to_peer: params.peer_to_peer,
+        }
+    }
+}
+
+impl ConnectionPool {
+    /// The shared connection is opened by the first WebSocket, the others reuse it while it is alive.
+    /// The WebSocket has its own connection unless the shared connection is requested.
+    #[instrument]
+    pub async fn connect(&self, params: &WebSocketParameters) -> crate::Result<Lease> {
+        if !params.shared {
+            let connection = dbus_connection(params).await?;
+            return Ok(Lease {
+                connection,
+                id: None,
+            });
+        }
+        // The lock is held while connecting, so the concurrent WebSockets do not open the duplicates
+        let mut state = self.0.lock().await;
+        let key = PoolKey::from(params);
+        if let Some(pooled) = state.connections.get_mut(&key) {
+            if pooled.failure.next().now_or_never().is_none() {
+                pooled.sessions += 1;
+                return Ok(Lease {
+                    connection: pooled.connection.clone(),
+                    id: Some(pooled.id),
+                });
+            }
+            // The WebSockets still using the broken connection keep it until they are closed
+            warn!(
+                "Shared connection {:?} is broken, it is replaced",
+                pooled.connection.unique_name()
+            );
+            state.connections.remove(&key);
+        }
+        let connection = dbus_connection(params).await?;
+        // The error messages have no member, so the rule doesn't match any message
+        let rule = MatchRule::builder()
+            .msg_type(Type::Error)
+            .member("ConnectionFailure")?
+            .build();
+        let failure = MessageStream::for_match_rule(rule, &connection, Some(1)).await?;
+        info!("Shared connection {:?} opened", connection.unique_name());
+        state.last_id += 1;
+        let id = state.last_id;
+        state.connections.insert(
+            key,
+            PooledConnection {
+                id,
+                connection: connection.clone(),
+                failure,
+                sessions: 1,
+            },
+        );
+        Ok(Lease {
+            connection,
+            id: Some(id),
+        })
+    }
+
+    /// Returns true if the connection is not used by the other WebSockets, so it can be closed.
+    /// The replaced broken connection is closed with its last reference.
+    #[instrument]
+    pub async fn disconnect(&self, params: &WebSocketParameters, id: Option<u64>) -> bool {
+        let Some(id) = id else {
+            return true;
+        };
+        let mut state = self.0.lock().await;
+        let key = PoolKey::from(params);
+        let Some(pooled) = state
+            .connections
+            .get_mut(&key)
+            .filter(|pooled| pooled.id == id)
+        else {
+            return false;
+        };
+        pooled.sessions -= 1;
+        if pooled.sessions > 0 {
+            return false;
+        }
+        if let Some(pooled) = state.connections.remove(&key) {
+            info!(
+                "Shared connection {:?} closed",
+                pooled.connection.unique_name()
+            );
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+    use tokio::sync::mpsc;
+
+    // Peer-to-peer server accepting the connections, the server side connections are sent to the test
+    fn server(
+        name: &str,
+    ) -> (
+        WebSocketParameters,
+        mpsc::UnboundedReceiver<zbus::Connection>,
+    ) {
+        let path =
+            std::env::temp_dir().join(format!("dbus-ws-proxy-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let connection = zbus::connection::Builder::unix_stream(stream)
+                    .server(zbus::Guid::generate())
+                    .unwrap()
+                    .p2p()
+                    .build()
+                    .await
+                    .unwrap();
+                if sender.send(connection).is_err() {
+                    break;
+                }
+            }
+        });
+        let params = WebSocketParameters {
+            connection: DBusConnectionTarget::Address,
+            address: Some(format!("unix:path={}", path.display())),
+            peer_to_peer: true,
+            shared: true,
+        };
+        (params, receiver)
+    }
+
+    #[tokio::test]
+    async fn shared_connection_is_closed_with_last_session() {
+        let (params, _connections) = server("sessions");
+        let pool = ConnectionPool::default();
+        let first = pool.connect(&params).await.unwrap();
+        let second = pool.connect(&params).await.unwrap();
+        assert!(first.id.is_some());
+        assert_eq!(first.id, second.id);
+        assert!(!pool.disconnect(&params, first.id).await);
+        assert!(pool.disconnect(&params, second.id).await);
+        let third = pool.connect(&params).await.unwrap();
+        assert_ne!(third.id, first.id);
+
+        let own = WebSocketParameters {
+            shared: false,
+            ..params.clone()
+        };
+        let own = pool.connect(&own).await.unwrap();
+        assert!(own.id.is_none());
+        assert!(pool.disconnect(&params, own.id).await);
+    }
+
+    #[tokio::test]
+    async fn broken_shared_connection_is_replaced() {
+        let (params, mut connections) = server("broken");
+        let pool = ConnectionPool::default();
+        let broken = pool.connect(&params).await.unwrap();
+        let mut messages = MessageStream::from(&broken.connection);
+        drop(connections.recv().await);
+        assert!(messages.next().await.unwrap().is_err());
+        let replaced = pool.connect(&params).await.unwrap();
+        assert_ne!(broken.id, replaced.id);
+        // The session of the broken connection doesn't close the replaced one
+        assert!(!pool.disconnect(&params, broken.id).await);
+        assert!(pool.disconnect(&params, replaced.id).await);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::interfaces;
+use crate::message::{OutputMessage, OwnedInterfaceKey};
+use crate::state::SubscriptionStream;
+use crate::value::Value;
+use std::collections::HashMap;
+use tokio_stream::StreamExt;
+use tracing::{instrument, trace, warn};
+use zbus::message::Type;
+use zbus::names::{OwnedBusName, OwnedInterfaceName, OwnedPropertyName};
+use zbus::MatchRule;
+use zvariant::OwnedValue;
+
+#[instrument(skip(connection))]
+pub async fn get(
+    connection: &zbus::Connection,
+    OwnedInterfaceKey {
+        destination,
+        path,
+        interface,
+    }: &OwnedInterfaceKey,
+    name: &str,
+) -> crate::Result<Value> {
+    let reply = connection
+        .call_method(
+            destination.as_ref(),
+            path,
+            Some(interfaces::PROPERTIES),
+            "Get",
+            &(interface, name),
+        )
+        .await?;
+    let value: OwnedValue = reply.body().deserialize()?;
+    Ok(value.into())
+}
+
+#[instrument(skip(connection))]
+pub async fn get_all(
+    connection: &zbus::Connection,
+    OwnedInterfaceKey {
+        destination,
+        path,
+        interface,
+    }: &OwnedInterfaceKey,
+) -> crate::Result<HashMap<String, Value>> {
+    let reply = connection
+        .call_method(
+            destination.as_ref(),
+            path,
+            Some(interfaces::PROPERTIES),
+            "GetAll",
+            &(interface,),
+        )
+        .await?;
+    let properties: HashMap<String, OwnedValue> = reply.body().deserialize()?;
+    Ok(properties
+        .into_iter()
+        .map(|(name, value)| (name, value.into()))
+        .collect())
+}
+
+/// The value is wrapped into a variant by the serializer.
+#[instrument(skip(connection))]
+pub async fn set(
+    connection: &zbus::Connection,
+    OwnedInterfaceKey {
+        destination,
+        path,
+        interface,
+    }: &OwnedInterfaceKey,
+    name: &OwnedPropertyName,
+    value: zvariant::Value<'_>,
+) -> crate::Result<()> {
+    connection
+        .call_method(
+            destination.as_ref(),
+            path,
+            Some(interfaces::PROPERTIES),
+            "Set",
+            &(interface, name, value),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Stream of the decoded `PropertiesChanged` signals of the interface.
+/// Invalidated properties are re-read, the ones that cannot be read are kept invalidated.
+#[instrument(skip(connection))]
+pub async fn changes(
+    connection: &zbus::Connection,
+    key: OwnedInterfaceKey,
+) -> crate::Result<SubscriptionStream> {
+    let mut rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .path(key.path.clone())?
+        .interface(interfaces::PROPERTIES)?
+        .member("PropertiesChanged")?
+        .arg(0, key.interface.to_string())?;
+    if let Some(destination) = &key.destination {
+        rule = rule.sender(destination.clone())?;
+    }
+    let stream = zbus::MessageStream::for_match_rule(rule.build(), connection, None).await?;
+    let connection = connection.clone();
+    Ok(Box::pin(stream.then(move |msg| {
+        let connection = connection.clone();
+        let destination = key.destination.clone();
+        async move { properties_changed(&connection, destination, msg?).await }
+    })))
+}
+
+/// Decodes the `PropertiesChanged` signal and re-reads the invalidated properties.
+pub async fn properties_changed(
+    connection: &zbus::Connection,
+    destination: Option<OwnedBusName>,
+    msg: zbus::Message,
+) -> crate::Result<OutputMessage> {
+    let (interface, changed, invalidated): (
+        OwnedInterfaceName,
+        HashMap<String, OwnedValue>,
+        Vec<String>,
+    ) = msg.body().deserialize()?;
+    let key = OwnedInterfaceKey {
+        destination,
+        path: msg
+            .header()
+            .path()
+            .ok_or(zbus::Error::MissingField)?
+            .to_owned()
+            .into(),
+        interface,
+    };
+    let mut changed: HashMap<String, Value> = changed
+        .into_iter()
+        .map(|(name, value)| (name, value.into()))
+        .collect();
+    let mut still_invalidated = Vec::new();
+    for name in invalidated {
+        match get(connection, &key, &name).await {
+            Ok(value) => {
+                trace!("Invalidated property '{}' is re-read", name);
+                changed.insert(name, value);
+            }
+            Err(err) => {
+                warn!("Invalidated property '{}' cannot be read: {}", name, err);
+                still_invalidated.push(name);
+            }
+        }
+    }
+    Ok(OutputMessage::PropertiesChanged {
+        key,
+        changed,
+        invalidated: still_invalidated,
+    })
+}
@@ -1,22 +1,117 @@
-use crate::message::{OutputMessage, OwnedSignalKey};
-use crate::value::Value;
-use crate::WebSocketEventHandler;
+use crate::message::{OutputMessage, SubscriptionId};
+use crate::signals::Routed;
+use crate::state::{StreamKey, SubscriptionKey, WebSocketState};
+use crate::{DBusConnectionTarget, WebSocketEventHandler};
 use std::ops::ControlFlow;
-use tracing::instrument;
-use zbus::Message;
+use std::sync::Arc;
+use tracing::{instrument, trace};
 
-#[derive(Default, Debug)]
-pub struct SignalHandler {}
+#[derive(Debug)]
+pub struct SignalHandler {
+    state: Arc<WebSocketState>,
+}
+
+impl SignalHandler {
+    pub fn new(state: Arc<WebSocketState>) -> Self {
+        Self { state }
+    }
+
+    // Ids of the subscriptions of the keys, and whether any of them requested the header
+    fn subscriptions(
+        &self,
+        keys: impl Fn(&StreamKey) -> bool,
+    ) -> Option<(Vec<SubscriptionId>, bool)> {
+        let subscriptions = self.state.subscriptions().deliver(keys);
+        if subscriptions.is_empty() {
+            return None;
+        }
+        let include_header = subscriptions
+            .iter()
+            .any(|(_, subscription)| subscription.include_header);
+        let ids = subscriptions.into_iter().map(|(id, _)| id).collect();
+        Some((ids, include_header))
+    }
+
+    // The message is delivered once for all subscriptions of the keys
+    fn deliver(
+        &self,
+        keys: impl Fn(&StreamKey) -> bool,
+        output_message: OutputMessage,
+    ) -> Option<OutputMessage> {
+        let Some((subscriptions, include_header)) = self.subscriptions(keys) else {
+            trace!("Message received after unsubscribe: {:?}", output_message);
+            return None;
+        };
+        Some(match output_message {
+            OutputMessage::Signal {
+                path,
+                interface,
+                name,
+                header,
+                args,
+                ..
+            } => OutputMessage::Signal {
+                subscriptions,
+                path,
+                interface,
+                name,
+                header: header.filter(|_| include_header),
+                args,
+            },
+            OutputMessage::ServiceVanished { name, .. } => OutputMessage::ServiceVanished {
+                subscriptions,
+                name,
+            },
+            OutputMessage::ServiceAppeared { name, owner, .. } => OutputMessage::ServiceAppeared {
+                subscriptions,
+                name,
+                owner,
+            },
+            OutputMessage::Monitored(mut monitored) => {
+                monitored.subscriptions = subscriptions;
+                OutputMessage::Monitored(monitored)
+            }
+            output_message => output_message,
+        })
+    }
+}
 
-impl WebSocketEventHandler<'static, (OwnedSignalKey, Message)> for SignalHandler {
+impl WebSocketEventHandler<'static, (StreamKey, crate::Result<OutputMessage>)> for SignalHandler {
     #[instrument]
     async fn handle(
         &self,
-        (key, message): (OwnedSignalKey, Message),
+        (key, event): (StreamKey, crate::Result<OutputMessage>),
     ) -> ControlFlow<Option<OutputMessage>, Option<OutputMessage>> {
-        match Value::try_to_array_from_body(&message.body()) {
-            Ok(args) => ControlFlow::Continue(Some(OutputMessage::Signal { key, args })),
-            Err(err) => ControlFlow::Continue(Some(err.into())),
+        let output_message = match event {
+            Ok(output_message) => output_message,
+            Err(err) => return ControlFlow::Continue(Some(err.into())),
+        };
+        if matches!(
+            key.key,
+            SubscriptionKey::ExportedObjects | SubscriptionKey::OwnedNames
+        ) {
+            return ControlFlow::Continue(Some(output_message));
         }
+        ControlFlow::Continue(self.deliver(|other| *other == key, output_message))
+    }
+}
+
+impl WebSocketEventHandler<'static, (DBusConnectionTarget, crate::Result<Routed>)>
+    for SignalHandler
+{
+    #[instrument]
+    async fn handle(
+        &self,
+        (connection, event): (DBusConnectionTarget, crate::Result<Routed>),
+    ) -> ControlFlow<Option<OutputMessage>, Option<OutputMessage>> {
+        let Routed { keys, message } = match event {
+            Ok(routed) => routed,
+            Err(err) => return ControlFlow::Continue(Some(err.into())),
+        };
+        // The signal is delivered once for all subscriptions it matches
+        ControlFlow::Continue(self.deliver(
+            |key| key.connection == connection && keys.contains(&key.key),
+            message,
+        ))
     }
 }
@@ -0,0 +1,537 @@
+use crate::delivery;
+use crate::error::Error;
+use crate::filter::{self, ArgFilter};
+use crate::message::{DeliveryOptions, OutputMessage, OwnedSignalKey};
+use crate::state::{SubscriptionKey, SubscriptionStream};
+use ordered_stream::{JoinMultiple, OrderedStream, OrderedStreamExt, Peekable, PollResult};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tracing::{instrument, trace};
+use zbus::fdo::{DBusProxy, NameOwnerChanged};
+use zbus::message::{Sequence, Type};
+use zbus::names::{BusName, OwnedUniqueName, OwnedWellKnownName, WellKnownName};
+use zbus::{MatchRule, MessageStream, OwnedMatchRule};
+
+/// Stream of the signals with the keys of the subscriptions they match.
+pub type RoutedStream = Pin<Box<dyn Stream<Item = crate::Result<Routed>> + Send>>;
+
+/// Signal event and the keys of all subscriptions it is delivered to.
+#[derive(Debug)]
+pub struct Routed {
+    pub keys: Vec<SubscriptionKey>,
+    pub message: OutputMessage,
+}
+
+/// Signal subscriptions of the connection.
+/// Every received signal is matched against all of them, so the signal matching
+/// several subscriptions is delivered once.
+#[derive(Debug, Default)]
+pub struct SignalRoutes(Mutex<RoutesInner>);
+
+#[derive(Debug, Default)]
+struct RoutesInner {
+    last_id: u64,
+    routes: HashMap<u64, Route>,
+    // Route ids of the streams, the messages of all streams are received in the receive order
+    ids: Vec<u64>,
+    streams: JoinMultiple<Vec<Peekable<MessageStream>>>,
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Route {
+    key: SubscriptionKey,
+    rule: OwnedMatchRule,
+    owner: Option<Owner>,
+    target: Target,
+    // The message matching several routes is received from the streams of all of them
+    last: Option<Sequence>,
+}
+
+// Signals of a well-known name are filtered by its current owner, that is tracked in the receive order.
+// The owner changes are reported as `ServiceVanished` and `ServiceAppeared` messages.
+#[derive(Debug)]
+struct Owner {
+    name: OwnedWellKnownName,
+    rule: OwnedMatchRule,
+    unique: Option<OwnedUniqueName>,
+}
+
+#[derive(Debug)]
+enum Target {
+    // Delivered as soon as received, together with the other matching subscriptions
+    Immediate(Vec<ArgFilter>),
+    // Delivered at the end of the window by the stream of the subscription
+    Delayed(UnboundedSender<crate::Result<OutputMessage>>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Signal,
+    Vanished(OwnedWellKnownName),
+    Appeared(OwnedWellKnownName, OwnedUniqueName),
+}
+
+/// Routes the signals matching the key, returns the stream of the subscription.
+#[instrument(skip(routes, connection))]
+pub async fn subscribe(
+    routes: &Arc<SignalRoutes>,
+    connection: &zbus::Connection,
+    key: OwnedSignalKey,
+    delivery: DeliveryOptions,
+) -> crate::Result<SubscriptionStream> {
+    let rule = match_rule(&key)?.into();
+    let key = SubscriptionKey::Signal {
+        key,
+        delivery: delivery.clone(),
+    };
+    route(routes, connection, key, rule, delivery).await
+}
+
+/// Routes the signals matching the raw match rule, returns the stream of the subscription.
+#[instrument(skip(routes, connection))]
+pub async fn subscribe_match_rule(
+    routes: &Arc<SignalRoutes>,
+    connection: &zbus::Connection,
+    rule: OwnedMatchRule,
+    delivery: DeliveryOptions,
+) -> crate::Result<SubscriptionStream> {
+    if rule
+        .msg_type()
+        .is_some_and(|msg_type| msg_type != Type::Signal)
+    {
+        return Err(Error::UnsupportedFormat(
+            "Only signal match rules are supported".into(),
+        ));
+    }
+    let key = SubscriptionKey::MatchRule {
+        rule: rule.clone(),
+        delivery: delivery.clone(),
+    };
+    route(routes, connection, key, rule, delivery).await
+}
+
+/// Stream of the routed signals, it is pending while there are no routes.
+pub fn routed(routes: Arc<SignalRoutes>) -> RoutedStream {
+    Box::pin(RoutedSignals {
+        routes,
+        ready: VecDeque::new(),
+    })
+}
+
+// The stream of the subscription only delivers the delayed signals, the route is removed with it.
+// The peer-to-peer connection has no message bus to track the owner.
+async fn route(
+    routes: &Arc<SignalRoutes>,
+    connection: &zbus::Connection,
+    key: SubscriptionKey,
+    rule: OwnedMatchRule,
+    delivery: DeliveryOptions,
+) -> crate::Result<SubscriptionStream> {
+    let (target, delayed) = if delivery.throttle_ms.is_none()
+        && delivery.debounce_ms.is_none()
+        && !delivery.latest_only
+    {
+        (Target::Immediate(delivery.filter), None)
+    } else {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stream = delivery::apply(Box::pin(UnboundedReceiverStream::new(receiver)), delivery)?;
+        (Target::Delayed(sender), Some(stream))
+    };
+    let sender = rule.sender().cloned().map(BusName::into_owned);
+    let mut streams = vec![MessageStream::for_match_rule(rule.clone(), connection, None).await?];
+    let owner = match sender.filter(|_| connection.is_bus()) {
+        Some(BusName::WellKnown(name)) => {
+            let owner_rule: OwnedMatchRule = MatchRule::builder()
+                .msg_type(Type::Signal)
+                .sender("org.freedesktop.DBus")?
+                .path("/org/freedesktop/DBus")?
+                .interface("org.freedesktop.DBus")?
+                .member("NameOwnerChanged")?
+                .arg(0, name.as_str())?
+                .build()
+                .into();
+            // The stream keeps the changes made while the owner is requested, they are applied after it
+            streams
+                .push(MessageStream::for_match_rule(owner_rule.clone(), connection, None).await?);
+            let unique = name_owner(connection, &name).await?;
+            Some(Owner {
+                name: name.into(),
+                rule: owner_rule,
+                unique,
+            })
+        }
+        _ => None,
+    };
+    let id = routes.insert(
+        Route {
+            key,
+            rule,
+            owner,
+            target,
+            last: None,
+        },
+        streams,
+    );
+    Ok(Box::pin(RouteStream {
+        routes: routes.clone(),
+        id,
+        delayed,
+    }))
+}
+
+impl SignalRoutes {
+    fn insert(&self, route: Route, streams: Vec<MessageStream>) -> u64 {
+        let mut inner = self.0.lock().unwrap();
+        inner.last_id += 1;
+        let id = inner.last_id;
+        for stream in streams {
+            inner.ids.push(id);
+            inner.streams.0.push(stream.peekable());
+        }
+        inner.routes.insert(id, route);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+        id
+    }
+
+    fn remove(&self, id: u64) {
+        let mut inner = self.0.lock().unwrap();
+        inner.routes.remove(&id);
+        let mut index = 0;
+        while index < inner.ids.len() {
+            if inner.ids[index] == id {
+                inner.ids.remove(index);
+                inner.streams.0.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+impl RoutesInner {
+    // The events of the routes are grouped, the delayed ones are sent to the streams of their subscriptions
+    fn route(&mut self, msg: &zbus::Message) -> Vec<crate::Result<Routed>> {
+        let position = msg.recv_position();
+        let mut signal = None;
+        let mut routed: Vec<(Event, Vec<SubscriptionKey>)> = Vec::new();
+        for route in self.routes.values_mut() {
+            if route.last.is_some_and(|last| last >= position) {
+                continue;
+            }
+            route.last = Some(position);
+            for event in route.events(msg) {
+                let filter = match &route.target {
+                    Target::Delayed(sender) => {
+                        let _ = sender.send(event.message(msg));
+                        continue;
+                    }
+                    Target::Immediate(filter) => filter,
+                };
+                if event == Event::Signal && !filter.is_empty() {
+                    let signal = signal.get_or_insert_with(|| event.message(msg));
+                    if matches!(signal, Ok(OutputMessage::Signal { args, .. }) if !filter::matches(filter, args))
+                    {
+                        continue;
+                    }
+                }
+                match routed.iter_mut().find(|(other, _)| *other == event) {
+                    Some((_, keys)) => keys.push(route.key.clone()),
+                    None => routed.push((event, vec![route.key.clone()])),
+                }
+            }
+        }
+        routed
+            .into_iter()
+            .map(|(event, keys)| {
+                let message = match event {
+                    Event::Signal => signal.take().unwrap_or_else(|| event.message(msg)),
+                    _ => event.message(msg),
+                }?;
+                Ok(Routed { keys, message })
+            })
+            .collect()
+    }
+}
+
+impl Route {
+    fn events(&mut self, msg: &zbus::Message) -> Vec<Event> {
+        let mut events = Vec::new();
+        if let Some(owner) = &mut self.owner {
+            if owner.rule.matches(msg).unwrap_or_default() {
+                events.extend(owner.change(msg));
+            }
+        }
+        let owned = self
+            .owner
+            .as_ref()
+            .is_none_or(|owner| msg.header().sender() == owner.unique.as_deref());
+        if is_signal(msg) && owned && self.rule.matches(msg).unwrap_or_default() {
+            events.push(Event::Signal);
+        }
+        events
+    }
+}
+
+impl Owner {
+    fn change(&mut self, msg: &zbus::Message) -> Option<Event> {
+        let new_owner = NameOwnerChanged::from_message(msg.clone()).and_then(|signal| {
+            let args = signal.args().ok()?;
+            args.new_owner()
+                .as_ref()
+                .map(|owner| owner.to_owned().into())
+        });
+        trace!("Owner of '{}' is changed to {:?}", self.name, new_owner);
+        match (
+            std::mem::replace(&mut self.unique, new_owner.clone()),
+            new_owner,
+        ) {
+            (Some(_), None) => Some(Event::Vanished(self.name.clone())),
+            (_, Some(owner)) => Some(Event::Appeared(self.name.clone(), owner)),
+            (None, None) => None,
+        }
+    }
+}
+
+impl Event {
+    // The subscriptions are set on delivery
+    fn message(&self, msg: &zbus::Message) -> crate::Result<OutputMessage> {
+        Ok(match self {
+            Event::Signal => return OutputMessage::from_signal(msg),
+            Event::Vanished(name) => OutputMessage::ServiceVanished {
+                subscriptions: Vec::new(),
+                name: name.clone(),
+            },
+            Event::Appeared(name, owner) => OutputMessage::ServiceAppeared {
+                subscriptions: Vec::new(),
+                name: name.clone(),
+                owner: owner.clone(),
+            },
+        })
+    }
+}
+
+struct RoutedSignals {
+    routes: Arc<SignalRoutes>,
+    ready: VecDeque<crate::Result<Routed>>,
+}
+
+impl Stream for RoutedSignals {
+    type Item = crate::Result<Routed>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(item) = this.ready.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+            let mut inner = this.routes.0.lock().unwrap();
+            match Pin::new(&mut inner.streams).poll_next_before(cx, None) {
+                Poll::Ready(PollResult::Item { data: Ok(msg), .. }) => {
+                    this.ready.extend(inner.route(&msg))
+                }
+                Poll::Ready(PollResult::Item { data: Err(err), .. }) => {
+                    return Poll::Ready(Some(Err(err.into())))
+                }
+                // The streams are polled again when the route is added
+                _ => {
+                    inner.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+// Stream of the subscription key, the immediate signals are delivered by the routed stream
+struct RouteStream {
+    routes: Arc<SignalRoutes>,
+    id: u64,
+    delayed: Option<SubscriptionStream>,
+}
+
+impl Stream for RouteStream {
+    type Item = crate::Result<OutputMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match &mut self.delayed {
+            Some(delayed) => delayed.as_mut().poll_next(cx),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for RouteStream {
+    fn drop(&mut self) {
+        self.routes.remove(self.id);
+    }
+}
+
+// The rule without the message type also matches the replies to the method calls
+fn is_signal(msg: &zbus::Message) -> bool {
+    msg.message_type() == Type::Signal
+}
+
+async fn name_owner(
+    connection: &zbus::Connection,
+    name: &WellKnownName<'_>,
+) -> crate::Result<Option<OwnedUniqueName>> {
+    match DBusProxy::new(connection)
+        .await?
+        .get_name_owner(BusName::from(name.clone()))
+        .await
+    {
+        Ok(owner) => Ok(Some(owner)),
+        Err(zbus::fdo::Error::NameHasNoOwner(_)) => Ok(None),
+        Err(err) => Err(zbus::Error::from(err).into()),
+    }
+}
+
+fn match_rule(key: &OwnedSignalKey) -> crate::Result<MatchRule<'_>> {
+    let mut rule = MatchRule::builder().msg_type(Type::Signal);
+    if let Some(destination) = &key.destination {
+        rule = rule.sender(destination)?;
+    }
+    match (&key.path, &key.path_namespace) {
+        (Some(_), Some(_)) => {
+            return Err(Error::UnsupportedFormat(
+                "Path and path namespace cannot be used together".into(),
+            ))
+        }
+        (Some(path), None) => rule = rule.path(path)?,
+        (None, Some(path_namespace)) => rule = rule.path_namespace(path_namespace)?,
+        (None, None) => {}
+    }
+    if let Some(interface) = &key.interface {
+        rule = rule.interface(interface)?;
+    }
+    if let Some(name) = &key.name {
+        rule = rule.member(name)?;
+    }
+    for (index, value) in &key.args {
+        rule = rule.arg(*index, value.as_str())?;
+    }
+    for (index, path) in &key.arg_paths {
+        rule = rule.arg_path(*index, path)?;
+    }
+    if let Some(namespace) = &key.arg0namespace {
+        rule = rule.arg0ns(namespace.as_str())?;
+    }
+    Ok(rule.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixStream;
+    use tokio_stream::StreamExt;
+
+    fn key(json: serde_json::Value) -> OwnedSignalKey {
+        serde_json::from_value(json).unwrap()
+    }
+
+    async fn peers() -> (zbus::Connection, zbus::Connection) {
+        let (server, client) = UnixStream::pair().unwrap();
+        let server = zbus::connection::Builder::unix_stream(server)
+            .server(zbus::Guid::generate())
+            .unwrap()
+            .p2p()
+            .build();
+        let client = zbus::connection::Builder::unix_stream(client).p2p().build();
+        let (server, client) = tokio::try_join!(server, client).unwrap();
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn signal_is_routed_once_to_all_matching_keys() {
+        let (server, client) = peers().await;
+        let routes = Arc::new(SignalRoutes::default());
+        let mut routed = routed(routes.clone());
+        let interface = key(serde_json::json!({"interface": "org.example.Test"}));
+        let name = key(serde_json::json!({"name": "Tick"}));
+        let other = key(serde_json::json!({"name": "Tock"}));
+        let mut streams = Vec::new();
+        for key in [interface.clone(), name.clone(), other] {
+            let stream = subscribe(&routes, &client, key, DeliveryOptions::default());
+            streams.push(stream.await.unwrap());
+        }
+
+        server
+            .emit_signal(
+                None::<()>,
+                "/org/example/Test",
+                "org.example.Test",
+                "Tick",
+                &("a",),
+            )
+            .await
+            .unwrap();
+        let Routed { keys, message } = routed.next().await.unwrap().unwrap();
+        assert!(matches!(message, OutputMessage::Signal { .. }));
+        assert_eq!(keys.len(), 2);
+        for key in [interface, name] {
+            assert!(keys.contains(&SubscriptionKey::Signal {
+                key,
+                delivery: DeliveryOptions::default(),
+            }));
+        }
+
+        // The route is removed with the stream of the subscription
+        drop(streams.remove(0));
+        server
+            .emit_signal(
+                None::<()>,
+                "/org/example/Test",
+                "org.example.Test",
+                "Tick",
+                &("b",),
+            )
+            .await
+            .unwrap();
+        let Routed { keys, .. } = routed.next().await.unwrap().unwrap();
+        assert_eq!(keys.len(), 1);
+    }
+
+    #[test]
+    fn partial_match_rule() {
+        let key = key(serde_json::json!({
+            "destination": "org.freedesktop.UDisks2",
+            "pathNamespace": "/org/freedesktop/UDisks2/block_devices",
+            "arg0namespace": "org.freedesktop.UDisks2",
+        }));
+        assert_eq!(
+            match_rule(&key).unwrap().to_string(),
+            "type='signal',sender='org.freedesktop.UDisks2',\
+            path_namespace='/org/freedesktop/UDisks2/block_devices',\
+            arg0namespace='org.freedesktop.UDisks2'"
+        );
+    }
+
+    #[test]
+    fn arguments_match_rule() {
+        let key = key(serde_json::json!({
+            "interface": "org.freedesktop.DBus.Properties",
+            "name": "PropertiesChanged",
+            "args": [[0, "org.bluez.Device1"]],
+            "argPaths": [[1, "/org/bluez"]],
+        }));
+        assert_eq!(
+            match_rule(&key).unwrap().to_string(),
+            "type='signal',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',\
+            arg0='org.bluez.Device1',arg1path='/org/bluez'"
+        );
+    }
+
+    #[test]
+    fn path_and_namespace_are_exclusive() {
+        let key = key(serde_json::json!({"path": "/org/bluez", "pathNamespace": "/org/bluez"}));
+        assert!(matches!(match_rule(&key), Err(Error::UnsupportedFormat(_))));
+    }
+}
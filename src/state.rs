@@ -1,43 +1,639 @@
-use crate::message::OwnedSignalKey;
+use crate::error::{Error, RequestError};
+use crate::export::ExportedObjects;
+use crate::introspection::IntrospectionCache;
+use crate::message::{
+    timestamp, DeliveryOptions, OutputMessage, OwnedInterfaceKey, OwnedObjectKey, OwnedSignalKey,
+    RequestId, SubscriptionId,
+};
+use crate::names::OwnedNames;
+use crate::pool::{ConnectionPool, Lease};
+use crate::signals::{self, RoutedStream, SignalRoutes};
+use crate::{DBusConnectionTarget, RequestResult, WebSocketParameters};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::future::{poll_fn, Future};
 use std::hash::Hash;
-use tokio::sync::Mutex;
-use tokio_stream::{Stream, StreamExt, StreamMap};
-use zbus::proxy::SignalStream;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use tokio::sync::oneshot;
+use tokio::task::{self, AbortHandle, JoinSet};
+use tokio_stream::{Stream, StreamMap};
+use zbus::OwnedMatchRule;
+
+/// Stream of the decoded subscription events.
+pub type SubscriptionStream = Pin<Box<dyn Stream<Item = crate::Result<OutputMessage>> + Send>>;
+
+#[derive(Serialize, Debug, Eq, PartialEq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum SubscriptionKey {
+    Signal {
+        #[serde(flatten)]
+        key: OwnedSignalKey,
+        #[serde(flatten)]
+        delivery: DeliveryOptions,
+    },
+    PropertyChanges(OwnedInterfaceKey),
+    ObjectManager(OwnedObjectKey),
+    MatchRule {
+        rule: OwnedMatchRule,
+        #[serde(flatten)]
+        delivery: DeliveryOptions,
+    },
+    Monitor {
+        rules: Vec<OwnedMatchRule>,
+    },
+    /// Incoming method calls of the exported objects, it is not a subscription of the client
+    ExportedObjects,
+    /// Ownership changes of the requested names, it is not a subscription of the client
+    OwnedNames,
+}
+
+/// Key of the subscription stream, the streams of the different connections are separate.
+#[derive(Serialize, Debug, Eq, PartialEq, Hash, Clone)]
+pub struct StreamKey {
+    pub connection: DBusConnectionTarget,
+    #[serde(flatten)]
+    pub key: SubscriptionKey,
+}
+
+impl StreamKey {
+    pub fn new(connection: DBusConnectionTarget, key: SubscriptionKey) -> Self {
+        Self { connection, key }
+    }
+}
 
 #[derive(Default, Debug)]
 pub struct WebSocketState {
-    signals: StreamMapState<OwnedSignalKey, SignalStream<'static>>,
+    signals: StreamMapState<StreamKey, SubscriptionStream>,
+    routed: StreamMapState<DBusConnectionTarget, RoutedStream>,
+    subscriptions: SubscriptionsState,
+    requests: RequestsState,
+    buses: tokio::sync::Mutex<HashMap<DBusConnectionTarget, Arc<BusState>>>,
 }
 
+/// DBus connection of the WebSocket with the objects and names registered on it.
 #[derive(Debug)]
-pub struct StreamMapState<K, S>(Mutex<StreamMap<K, S>>);
+pub struct BusState {
+    params: WebSocketParameters,
+    pool_id: Option<u64>,
+    connection: zbus::Connection,
+    introspection: IntrospectionCache,
+    exported: Arc<ExportedObjects>,
+    names: Arc<OwnedNames>,
+    signals: Arc<SignalRoutes>,
+}
+
+// The lock is never held across an await point, so the streams can be modified
+// from the request tasks while the session loop is waiting for the next item.
+pub struct StreamMapState<K, S>(Mutex<StreamMapInner<K, S>>);
+
+struct StreamMapInner<K, S> {
+    streams: StreamMap<K, S>,
+    waker: Option<Waker>,
+}
 
 impl<K, S> StreamMapState<K, S>
 where
     K: Hash + Eq + Clone + Unpin,
     S: Stream + Unpin,
 {
-    pub async fn insert(&self, key: K, value: S) -> Option<S> {
-        self.0.lock().await.insert(key, value)
+    pub fn insert(&self, key: K, value: S) -> Option<S> {
+        let mut inner = self.0.lock().unwrap();
+        let previous = inner.streams.insert(key, value);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+        previous
     }
 
-    pub async fn remove(&self, key: &K) -> Option<S> {
-        self.0.lock().await.remove(key)
+    pub fn remove(&self, key: &K) -> Option<S> {
+        self.0.lock().unwrap().streams.remove(key)
     }
 
     pub async fn next(&self) -> Option<(K, S::Item)> {
-        self.0.lock().await.next().await
+        poll_fn(|cx| {
+            let mut inner = self.0.lock().unwrap();
+            match Pin::new(&mut inner.streams).poll_next(cx) {
+                Poll::Ready(Some(item)) => Poll::Ready(Some(item)),
+                Poll::Ready(None) | Poll::Pending => {
+                    inner.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl<K: Debug, S> Debug for StreamMapState<K, S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.lock().unwrap().streams.keys())
+            .finish()
     }
 }
 
 impl<K, S> Default for StreamMapState<K, S> {
     fn default() -> Self {
-        Self(Mutex::new(StreamMap::default()))
+        Self(Mutex::new(StreamMapInner {
+            streams: StreamMap::default(),
+            waker: None,
+        }))
+    }
+}
+
+/// Subscription of the client, the subscriptions with the same key share the stream.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    #[serde(flatten)]
+    pub key: StreamKey,
+    pub include_header: bool,
+    /// Milliseconds since the Unix epoch when the subscription is created
+    pub created_at: u64,
+    /// Number of the messages delivered to the client
+    pub delivered: u64,
+}
+
+/// Active subscription reported to the client.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionInfo {
+    pub subscription_id: SubscriptionId,
+    #[serde(flatten)]
+    pub subscription: Subscription,
+}
+
+impl Subscription {
+    pub fn new(key: StreamKey, include_header: bool) -> Self {
+        Self {
+            key,
+            include_header,
+            created_at: timestamp(),
+            delivered: 0,
+        }
+    }
+}
+
+// Every subscription has an unique id, the stream of the key is removed with the last subscription.
+#[derive(Default, Debug)]
+pub struct SubscriptionsState(Mutex<SubscriptionsInner>);
+
+#[derive(Default, Debug)]
+struct SubscriptionsInner {
+    last_id: SubscriptionId,
+    subscriptions: HashMap<SubscriptionId, Subscription>,
+}
+
+impl SubscriptionsInner {
+    fn contains_key(&self, key: &StreamKey) -> bool {
+        self.subscriptions
+            .values()
+            .any(|subscription| subscription.key == *key)
+    }
+}
+
+impl SubscriptionsState {
+    /// Returns the id of the added subscription, and true if it is the first subscription of the key.
+    pub fn add(&self, subscription: Subscription) -> (SubscriptionId, bool) {
+        let mut inner = self.0.lock().unwrap();
+        let first = !inner.contains_key(&subscription.key);
+        inner.last_id += 1;
+        let id = inner.last_id;
+        inner.subscriptions.insert(id, subscription);
+        (id, first)
+    }
+
+    /// Adds the subscription only if its key already has the subscriptions, so the stream exists.
+    pub fn add_to_existing(&self, subscription: &Subscription) -> Option<SubscriptionId> {
+        let mut inner = self.0.lock().unwrap();
+        if !inner.contains_key(&subscription.key) {
+            return None;
+        }
+        inner.last_id += 1;
+        let id = inner.last_id;
+        inner.subscriptions.insert(id, subscription.clone());
+        Some(id)
+    }
+
+    pub fn get(&self, id: SubscriptionId) -> Option<Subscription> {
+        self.0.lock().unwrap().subscriptions.get(&id).cloned()
+    }
+
+    /// Returns the key of the removed subscription if the key has no subscriptions left.
+    pub fn remove(&self, id: SubscriptionId) -> Option<StreamKey> {
+        let mut inner = self.0.lock().unwrap();
+        let subscription = inner.subscriptions.remove(&id)?;
+        (!inner.contains_key(&subscription.key)).then_some(subscription.key)
+    }
+
+    /// Removes the subscriptions of the matching keys, returns the removed keys.
+    pub fn remove_keys(&self, matches: impl Fn(&StreamKey) -> bool) -> HashSet<StreamKey> {
+        let mut removed = HashSet::new();
+        self.0
+            .lock()
+            .unwrap()
+            .subscriptions
+            .retain(|_, subscription| {
+                if matches(&subscription.key) {
+                    removed.insert(subscription.key.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+        removed
+    }
+
+    /// Counts the message delivered to the subscriptions of the matching keys, returns them ordered by id.
+    pub fn deliver(
+        &self,
+        matches: impl Fn(&StreamKey) -> bool,
+    ) -> Vec<(SubscriptionId, Subscription)> {
+        let mut inner = self.0.lock().unwrap();
+        let mut subscriptions: Vec<_> = inner
+            .subscriptions
+            .iter_mut()
+            .filter(|(_, subscription)| matches(&subscription.key))
+            .map(|(id, subscription)| {
+                subscription.delivered += 1;
+                (*id, subscription.clone())
+            })
+            .collect();
+        subscriptions.sort_by_key(|(id, _)| *id);
+        subscriptions
+    }
+
+    /// All subscriptions ordered by id.
+    pub fn list(&self) -> Vec<(SubscriptionId, Subscription)> {
+        let inner = self.0.lock().unwrap();
+        let mut subscriptions: Vec<_> = inner
+            .subscriptions
+            .iter()
+            .map(|(id, subscription)| (*id, subscription.clone()))
+            .collect();
+        subscriptions.sort_by_key(|(id, _)| *id);
+        subscriptions
+    }
+}
+
+type RequestTaskResult = RequestResult<Option<OutputMessage>>;
+
+// Requests that are processed concurrently, the results are returned in the completion order.
+// The ordered requests are processed one by one in the spawn order.
+#[derive(Default, Debug)]
+pub struct RequestsState(Mutex<RequestsInner>);
+
+#[derive(Default, Debug)]
+struct RequestsInner {
+    tasks: JoinSet<RequestTaskResult>,
+    pending: HashMap<task::Id, PendingRequest>,
+    // Closed when the last spawned ordered request is completed or aborted
+    last_ordered: Option<oneshot::Receiver<()>>,
+}
+
+#[derive(Debug)]
+struct PendingRequest {
+    request_id: Option<RequestId>,
+    connection: DBusConnectionTarget,
+    handle: AbortHandle,
+}
+
+impl RequestsState {
+    /// The result of the request is returned with the connection the request is sent to.
+    pub fn spawn<F>(&self, request_id: Option<RequestId>, connection: DBusConnectionTarget, task: F)
+    where
+        F: Future<Output = RequestTaskResult> + Send + 'static,
+    {
+        let mut inner = self.0.lock().unwrap();
+        let handle = inner.tasks.spawn(task);
+        let request = PendingRequest {
+            request_id,
+            connection,
+            handle,
+        };
+        inner.pending.insert(request.handle.id(), request);
+    }
+
+    /// Same as `spawn`, but the request is started after the previously spawned ordered request is completed.
+    pub fn spawn_ordered<F>(
+        &self,
+        request_id: Option<RequestId>,
+        connection: DBusConnectionTarget,
+        task: F,
+    ) where
+        F: Future<Output = RequestTaskResult> + Send + 'static,
+    {
+        let (completed, last_ordered) = oneshot::channel::<()>();
+        let previous = self.0.lock().unwrap().last_ordered.replace(last_ordered);
+        self.spawn(request_id, connection, async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            let result = task.await;
+            drop(completed);
+            result
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().tasks.len()
+    }
+
+    /// Aborts the pending requests with the specified id.
+    /// Returns false if there are no such requests.
+    pub fn cancel(&self, request_id: RequestId) -> bool {
+        let inner = self.0.lock().unwrap();
+        let mut cancelled = false;
+        for request in inner
+            .pending
+            .values()
+            .filter(|request| request.request_id == Some(request_id))
+        {
+            request.handle.abort();
+            cancelled = true;
+        }
+        cancelled
+    }
+
+    pub fn abort_all(&self) {
+        self.0.lock().unwrap().tasks.abort_all();
+    }
+
+    pub async fn next(&self) -> Option<(DBusConnectionTarget, RequestTaskResult)> {
+        poll_fn(|cx| {
+            let mut inner = self.0.lock().unwrap();
+            inner.tasks.poll_join_next_with_id(cx).map(|result| {
+                result.map(|result| {
+                    let id = match &result {
+                        Ok((id, _)) => *id,
+                        Err(err) => err.id(),
+                    };
+                    let (request_id, connection) = match inner.pending.remove(&id) {
+                        Some(request) => (request.request_id, request.connection),
+                        None => (None, DBusConnectionTarget::default()),
+                    };
+                    let result = match result {
+                        Ok((_, result)) => result,
+                        Err(err) if err.is_cancelled() => {
+                            Err(RequestError::new(request_id, Error::Cancelled))
+                        }
+                        Err(err) => Err(RequestError::new(request_id, err)),
+                    };
+                    (connection, result)
+                })
+            })
+        })
+        .await
     }
 }
 
 impl WebSocketState {
-    pub fn signals(&self) -> &StreamMapState<OwnedSignalKey, SignalStream<'static>> {
+    pub fn signals(&self) -> &StreamMapState<StreamKey, SubscriptionStream> {
         &self.signals
     }
+
+    pub fn routed(&self) -> &StreamMapState<DBusConnectionTarget, RoutedStream> {
+        &self.routed
+    }
+
+    pub fn subscriptions(&self) -> &SubscriptionsState {
+        &self.subscriptions
+    }
+
+    pub fn requests(&self) -> &RequestsState {
+        &self.requests
+    }
+
+    /// The connection is opened on the first use, the parameters must be the parameters of the target.
+    pub async fn bus(
+        &self,
+        params: WebSocketParameters,
+        pool: &ConnectionPool,
+    ) -> crate::Result<Arc<BusState>> {
+        let mut buses = self.buses.lock().await;
+        let target = params.target();
+        if let Some(bus) = buses.get(&target) {
+            return Ok(bus.clone());
+        }
+        let Lease { connection, id } = pool.connect(&params).await?;
+        let bus = Arc::new(BusState {
+            params,
+            pool_id: id,
+            connection,
+            introspection: IntrospectionCache::default(),
+            exported: Arc::default(),
+            names: Arc::default(),
+            signals: Arc::default(),
+        });
+        self.routed
+            .insert(target, signals::routed(bus.signals.clone()));
+        buses.insert(target, bus.clone());
+        Ok(bus)
+    }
+
+    /// Removes the opened connections to close them.
+    pub async fn take_buses(&self) -> Vec<Arc<BusState>> {
+        self.buses
+            .lock()
+            .await
+            .drain()
+            .map(|(_, bus)| bus)
+            .collect()
+    }
+}
+
+impl BusState {
+    pub fn connection(&self) -> &zbus::Connection {
+        &self.connection
+    }
+
+    pub fn introspection(&self) -> &IntrospectionCache {
+        &self.introspection
+    }
+
+    pub fn exported(&self) -> &Arc<ExportedObjects> {
+        &self.exported
+    }
+
+    pub fn names(&self) -> &Arc<OwnedNames> {
+        &self.names
+    }
+
+    pub fn signals(&self) -> &Arc<SignalRoutes> {
+        &self.signals
+    }
+
+    /// Fails the pending incoming calls, releases the requested names and leaves the pool.
+    /// Returns true if the connection is not used by the other WebSockets, so it can be shut down.
+    pub async fn close(&self, pool: &ConnectionPool) -> bool {
+        self.exported.close(&self.connection).await;
+        self.names.release_all(&self.connection).await;
+        pool.disconnect(&self.params, self.pool_id).await
+    }
+
+    /// The connection is shut down after all its references are dropped, including the streams.
+    pub async fn shutdown(self) {
+        self.connection.graceful_shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorType;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn stream_inserted_while_waiting_is_polled() {
+        let state = Arc::new(StreamMapState::default());
+        let waiting = tokio::spawn({
+            let state = state.clone();
+            async move { state.next().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        state.insert(1, tokio_stream::iter(vec!["item"]));
+        let next = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("Inserted stream must wake up the waiting task")
+            .unwrap();
+        assert_eq!(next, Some((1, "item")));
+    }
+
+    #[test]
+    fn stream_is_removed_with_last_subscription() {
+        let subscriptions = SubscriptionsState::default();
+        let subscription = |rule: &str| {
+            let key = SubscriptionKey::MatchRule {
+                rule: OwnedMatchRule::try_from(rule).unwrap(),
+                delivery: DeliveryOptions::default(),
+            };
+            Subscription::new(StreamKey::new(DBusConnectionTarget::Session, key), false)
+        };
+        assert!(subscriptions
+            .add_to_existing(&subscription("type='signal',member='Tick'"))
+            .is_none());
+        assert!(subscriptions.list().is_empty());
+        let (first, is_first) = subscriptions.add(subscription("type='signal',member='Tick'"));
+        assert!(is_first);
+        let (second, is_first) = subscriptions.add(subscription("member='Tick',type='signal'"));
+        assert!(!is_first);
+        assert_ne!(first, second);
+        let key = subscription("type='signal',member='Tick'").key;
+        let ids: Vec<_> = subscriptions
+            .deliver(|other| *other == key)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, vec![first, second]);
+        assert!(subscriptions.remove(first).is_none());
+        assert_eq!(subscriptions.remove(second), Some(key));
+        assert!(subscriptions.remove(second).is_none());
+    }
+
+    #[test]
+    fn listed_subscriptions_count_delivered_messages() {
+        let subscriptions = SubscriptionsState::default();
+        let key = StreamKey::new(
+            DBusConnectionTarget::System,
+            SubscriptionKey::Signal {
+                key: serde_json::from_str(r#"{"interface":"org.example.Test","member":"Tick"}"#)
+                    .unwrap(),
+                delivery: DeliveryOptions {
+                    throttle_ms: Some(100),
+                    ..Default::default()
+                },
+            },
+        );
+        let (id, _) = subscriptions.add(Subscription::new(key.clone(), true));
+        subscriptions.deliver(|other| *other == key);
+        subscriptions.deliver(|other| *other == key);
+        let [(listed_id, subscription)] = subscriptions.list().try_into().unwrap();
+        assert_eq!(listed_id, id);
+        let json = serde_json::to_value(SubscriptionInfo {
+            subscription_id: id,
+            subscription,
+        })
+        .unwrap();
+        assert_eq!(json["subscriptionId"], id);
+        assert_eq!(json["connection"], "system");
+        assert_eq!(json["signal"]["interface"], "org.example.Test");
+        assert_eq!(json["signal"]["throttleMs"], 100);
+        assert_eq!(json["includeHeader"], true);
+        assert_eq!(json["delivered"], 2);
+        assert!(json["createdAt"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn requests_are_returned_in_completion_order() {
+        let requests = RequestsState::default();
+        requests.spawn(Some(1), DBusConnectionTarget::Session, async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(Some(OutputMessage::Success {
+                request_id: Some(1),
+            }))
+        });
+        requests.spawn(Some(2), DBusConnectionTarget::System, async {
+            Ok(Some(OutputMessage::Success {
+                request_id: Some(2),
+            }))
+        });
+        assert_eq!(requests.len(), 2);
+        let Some((connection, Ok(Some(OutputMessage::Success { request_id })))) =
+            requests.next().await
+        else {
+            panic!("Unexpected request result");
+        };
+        assert_eq!(request_id, Some(2));
+        assert_eq!(connection, DBusConnectionTarget::System);
+    }
+
+    #[tokio::test]
+    async fn ordered_requests_are_returned_in_spawn_order() {
+        let requests = RequestsState::default();
+        for (request_id, delay) in [(1, 50), (2, 0)] {
+            requests.spawn_ordered(
+                Some(request_id),
+                DBusConnectionTarget::Session,
+                async move {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    Ok(Some(OutputMessage::Success {
+                        request_id: Some(request_id),
+                    }))
+                },
+            );
+        }
+        let mut completed = Vec::new();
+        while let Some((_, Ok(Some(OutputMessage::Success { request_id })))) = requests.next().await
+        {
+            completed.push(request_id);
+        }
+        assert_eq!(completed, vec![Some(1), Some(2)]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_returns_error() {
+        let requests = RequestsState::default();
+        requests.spawn(Some(1), DBusConnectionTarget::Session, async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(None)
+        });
+        assert!(requests.cancel(1));
+        assert!(!requests.cancel(2));
+        let Some((_, Err(err))) = requests.next().await else {
+            panic!("Cancelled request must return an error");
+        };
+        assert!(matches!(
+            OutputMessage::from(err),
+            OutputMessage::Error {
+                request_id: Some(1),
+                error_type: ErrorType::Cancelled,
+                ..
+            }
+        ));
+        assert_eq!(requests.len(), 0);
+    }
 }
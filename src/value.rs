@@ -3,7 +3,9 @@ use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::ops::Deref;
+use std::str::FromStr;
 use thiserror::Error;
 use zbus::message::Body;
 use zvariant::signature::Child;
@@ -15,8 +17,33 @@ pub enum Error {
     ElementsTypeIsDifferent,
     #[error("The structure is empty")]
     EmptyStructure,
+    #[error("The signature '{signature}' expects {expected} arguments, but {actual} were given")]
+    ArgumentsCountMismatch {
+        signature: BodySignature,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Argument {index}: {source}")]
+    InvalidArgument {
+        index: usize,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error("Expected a value of type '{expected}', but got {actual}")]
+    TypeMismatch {
+        expected: zvariant::Signature,
+        actual: serde_json::Value,
+    },
+    #[error("The type '{0}' cannot be converted from JSON")]
+    UnsupportedType(zvariant::Signature),
 }
 
+/// Signature of a message body, a sequence of complete types, one for each argument.
+/// Unlike [`zvariant::Signature`], a single structure argument `(si)` is not confused
+/// with two arguments `si`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct BodySignature(Vec<zvariant::Signature>);
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 pub enum Value {
@@ -326,7 +353,7 @@ impl From<ContainerType> for zvariant::Signature {
             ContainerType::Struct { fields } => {
                 let field_signatures: Vec<zvariant::Signature> = fields
                     .into_iter()
-                    .map(|f| Into::<zvariant::Signature>::into(f))
+                    .map(Into::<zvariant::Signature>::into)
                     .collect();
                 Self::Structure(field_signatures.into())
             }
@@ -445,7 +472,269 @@ pub fn try_structure_from_fields(
     for value in fields {
         builder.push_value(value.try_into()?);
     }
-    Ok(builder.build().map_err(|_| Error::EmptyStructure)?)
+    builder.build().map_err(|_| Error::EmptyStructure)
+}
+
+// JSON -> zbus
+impl BodySignature {
+    pub fn types(&self) -> &[zvariant::Signature] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for BodySignature {
+    type Err = zvariant::signature::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+        match zvariant::Signature::from_str(&format!("({s})"))? {
+            zvariant::Signature::Structure(fields) => Ok(Self(fields.iter().cloned().collect())),
+            signature => Ok(Self(vec![signature])),
+        }
+    }
+}
+
+impl From<Vec<zvariant::Signature>> for BodySignature {
+    fn from(value: Vec<zvariant::Signature>) -> Self {
+        Self(value)
+    }
+}
+
+impl Display for BodySignature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for signature in &self.0 {
+            Display::fmt(signature, f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for BodySignature {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BodySignature {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let signature = String::deserialize(deserializer)?;
+        Self::from_str(&signature).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Converts plain JSON arguments to the message body by walking the body signature.
+/// Returns `None` if the signature and the arguments are empty.
+pub fn try_structure_from_json(
+    args: Vec<serde_json::Value>,
+    signature: &BodySignature,
+) -> Result<Option<zvariant::Structure<'static>>, Error> {
+    if args.len() != signature.0.len() {
+        return Err(Error::ArgumentsCountMismatch {
+            signature: signature.clone(),
+            expected: signature.0.len(),
+            actual: args.len(),
+        });
+    }
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = zvariant::StructureBuilder::new();
+    for (index, (arg, signature)) in args.into_iter().zip(signature.0.iter()).enumerate() {
+        let value = try_value_from_json(arg, signature).map_err(|err| Error::InvalidArgument {
+            index,
+            source: Box::new(err),
+        })?;
+        builder.push_value(value);
+    }
+    builder.build().map(Some).map_err(|_| Error::EmptyStructure)
+}
+
+/// Converts plain JSON value to the value of the given type.
+/// Values in the variant positions are wrapped automatically.
+pub fn try_value_from_json(
+    json: serde_json::Value,
+    signature: &zvariant::Signature,
+) -> Result<zvariant::Value<'static>, Error> {
+    let mismatch = |json: serde_json::Value| Error::TypeMismatch {
+        expected: signature.clone(),
+        actual: json,
+    };
+    Ok(match signature {
+        zvariant::Signature::U8 => zvariant::Value::U8(json_integer(json, signature)?),
+        zvariant::Signature::Bool => match json {
+            serde_json::Value::Bool(value) => value.into(),
+            json => return Err(mismatch(json)),
+        },
+        zvariant::Signature::I16 => zvariant::Value::I16(json_integer(json, signature)?),
+        zvariant::Signature::U16 => zvariant::Value::U16(json_integer(json, signature)?),
+        zvariant::Signature::I32 => zvariant::Value::I32(json_integer(json, signature)?),
+        zvariant::Signature::U32 => zvariant::Value::U32(json_integer(json, signature)?),
+        zvariant::Signature::I64 => zvariant::Value::I64(json_integer(json, signature)?),
+        zvariant::Signature::U64 => zvariant::Value::U64(json_integer(json, signature)?),
+        zvariant::Signature::F64 => match json.as_f64() {
+            Some(value) => value.into(),
+            None => return Err(mismatch(json)),
+        },
+        zvariant::Signature::Str
+        | zvariant::Signature::Signature
+        | zvariant::Signature::ObjectPath => match json {
+            serde_json::Value::String(value) => try_basic_value_from_string(value, signature)?,
+            json => return Err(mismatch(json)),
+        },
+        zvariant::Signature::Variant => {
+            zvariant::Value::Value(Box::new(try_variant_from_json(json)?))
+        }
+        zvariant::Signature::Array(child) => match json {
+            serde_json::Value::Array(values) => {
+                let mut array = zvariant::Array::new(child.signature());
+                for value in values {
+                    array
+                        .append(try_value_from_json(value, child.signature())?)
+                        .map_err(|_| Error::ElementsTypeIsDifferent)?;
+                }
+                array.into()
+            }
+            json => return Err(mismatch(json)),
+        },
+        zvariant::Signature::Dict { key, value } => match json {
+            serde_json::Value::Object(entries) => {
+                let mut dict = zvariant::Dict::new(key.signature(), value.signature());
+                for (k, v) in entries {
+                    dict.append(
+                        try_basic_value_from_string(k, key.signature())?,
+                        try_value_from_json(v, value.signature())?,
+                    )
+                    .map_err(|_| Error::ElementsTypeIsDifferent)?;
+                }
+                dict.into()
+            }
+            json => return Err(mismatch(json)),
+        },
+        zvariant::Signature::Structure(fields) => match json {
+            serde_json::Value::Array(values) if values.len() == fields.len() => {
+                let mut builder = zvariant::StructureBuilder::new();
+                for (value, field) in values.into_iter().zip(fields.iter()) {
+                    builder.push_value(try_value_from_json(value, field)?);
+                }
+                builder.build().map_err(|_| Error::EmptyStructure)?.into()
+            }
+            json => return Err(mismatch(json)),
+        },
+        zvariant::Signature::Unit | zvariant::Signature::Fd => {
+            return Err(Error::UnsupportedType(signature.clone()))
+        }
+    })
+}
+
+// Explicitly typed value is used as is, otherwise the type is inferred from the JSON value.
+fn try_variant_from_json(json: serde_json::Value) -> Result<zvariant::Value<'static>, Error> {
+    if json.is_object() {
+        if let Ok(value) = serde_json::from_value::<Value>(json.clone()) {
+            return value.try_into();
+        }
+    }
+    try_value_from_json(json.clone(), &infer_signature(&json)?)
+}
+
+fn infer_signature(json: &serde_json::Value) -> Result<zvariant::Signature, Error> {
+    Ok(match json {
+        serde_json::Value::Null => {
+            return Err(Error::TypeMismatch {
+                expected: zvariant::Signature::Variant,
+                actual: serde_json::Value::Null,
+            })
+        }
+        serde_json::Value::Bool(_) => zvariant::Signature::Bool,
+        serde_json::Value::Number(number) => {
+            if number.as_i64().is_some_and(|n| i32::try_from(n).is_ok()) {
+                zvariant::Signature::I32
+            } else if number.is_i64() {
+                zvariant::Signature::I64
+            } else if number.is_u64() {
+                zvariant::Signature::U64
+            } else {
+                zvariant::Signature::F64
+            }
+        }
+        serde_json::Value::String(_) => zvariant::Signature::Str,
+        serde_json::Value::Array(values) => {
+            let mut signatures = values.iter().map(infer_signature);
+            let element = match signatures.next().transpose()? {
+                Some(first) => {
+                    let mut element = first;
+                    for signature in signatures {
+                        if signature? != element {
+                            element = zvariant::Signature::Variant;
+                            break;
+                        }
+                    }
+                    element
+                }
+                None => zvariant::Signature::Variant,
+            };
+            zvariant::Signature::array(element)
+        }
+        serde_json::Value::Object(_) => {
+            zvariant::Signature::dict(zvariant::Signature::Str, zvariant::Signature::Variant)
+        }
+    })
+}
+
+fn json_integer<T>(json: serde_json::Value, signature: &zvariant::Signature) -> Result<T, Error>
+where
+    T: TryFrom<i64> + TryFrom<u64>,
+{
+    let value = match &json {
+        serde_json::Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+            (Some(value), _) => T::try_from(value).ok(),
+            (None, Some(value)) => T::try_from(value).ok(),
+            (None, None) => None,
+        },
+        _ => None,
+    };
+    value.ok_or_else(|| Error::TypeMismatch {
+        expected: signature.clone(),
+        actual: json,
+    })
+}
+
+// Dict keys are always strings in JSON, so the basic types are parsed from the string.
+fn try_basic_value_from_string(
+    value: String,
+    signature: &zvariant::Signature,
+) -> Result<zvariant::Value<'static>, Error> {
+    let mismatch = |value: String| Error::TypeMismatch {
+        expected: signature.clone(),
+        actual: serde_json::Value::String(value),
+    };
+    fn parse<T: FromStr>(value: &str) -> Option<T> {
+        value.parse().ok()
+    }
+    Ok(match signature {
+        zvariant::Signature::Str => value.into(),
+        zvariant::Signature::Signature => parse::<zvariant::Signature>(&value)
+            .ok_or_else(|| mismatch(value))?
+            .into(),
+        zvariant::Signature::ObjectPath => zvariant::OwnedObjectPath::try_from(value.as_str())
+            .map_err(|_| mismatch(value))?
+            .into(),
+        zvariant::Signature::U8 => parse::<u8>(&value).ok_or_else(|| mismatch(value))?.into(),
+        zvariant::Signature::Bool => parse::<bool>(&value).ok_or_else(|| mismatch(value))?.into(),
+        zvariant::Signature::I16 => parse::<i16>(&value).ok_or_else(|| mismatch(value))?.into(),
+        zvariant::Signature::U16 => parse::<u16>(&value).ok_or_else(|| mismatch(value))?.into(),
+        zvariant::Signature::I32 => parse::<i32>(&value).ok_or_else(|| mismatch(value))?.into(),
+        zvariant::Signature::U32 => parse::<u32>(&value).ok_or_else(|| mismatch(value))?.into(),
+        zvariant::Signature::I64 => parse::<i64>(&value).ok_or_else(|| mismatch(value))?.into(),
+        zvariant::Signature::U64 => parse::<u64>(&value).ok_or_else(|| mismatch(value))?.into(),
+        zvariant::Signature::F64 => parse::<f64>(&value).ok_or_else(|| mismatch(value))?.into(),
+        _ => return Err(Error::UnsupportedType(signature.clone())),
+    })
 }
 
 // zbus -> value
@@ -740,4 +1029,83 @@ mod tests {
         let json = r#"{"type":"struct","value":[{"type":"i32","value":123},{"type":"string","value":"s1"},{"type":"array","value":[{"type":"i32","value":5},{"type":"i32","value":6},{"type":"i32","value":7}]}]}"#;
         assert_eq!(serde_json::to_string(&value).unwrap(), json.to_string());
     }
+
+    #[test]
+    fn body_signature() {
+        let signature = BodySignature::from_str("sa{sv}u").unwrap();
+        assert_eq!(signature.types().len(), 3);
+        assert_eq!(signature.to_string(), "sa{sv}u");
+
+        let signature = BodySignature::from_str("(si)").unwrap();
+        assert_eq!(signature.types().len(), 1);
+        assert_eq!(signature.to_string(), "(si)");
+
+        assert!(BodySignature::from_str("").unwrap().is_empty());
+        assert!(BodySignature::from_str("a{s").is_err());
+    }
+
+    #[test]
+    fn structure_from_json() {
+        let signature = BodySignature::from_str("sa{sv}uas(ib)").unwrap();
+        let args = serde_json::from_str(
+            r#"["app", {"urgency": 1, "hint": "x", "typed": {"type": "u8", "value": 2}}, 5000, ["a", "b"], [-1, true]]"#,
+        )
+        .unwrap();
+        let structure = try_structure_from_json(args, &signature).unwrap().unwrap();
+        assert_eq!(structure.signature().to_string(), "(sa{sv}uas(ib))");
+
+        let zvariant::Value::Dict(hints) = &structure.fields()[1] else {
+            panic!("Dict expected");
+        };
+        let urgency: Option<&zvariant::Value> = hints.get(&"urgency").unwrap();
+        assert_eq!(urgency, Some(&zvariant::Value::I32(1)));
+        let typed: Option<&zvariant::Value> = hints.get(&"typed").unwrap();
+        assert_eq!(typed, Some(&zvariant::Value::U8(2)));
+    }
+
+    #[test]
+    fn structure_from_json_errors() {
+        fn error(signature: &str, args: &str) -> String {
+            let signature = BodySignature::from_str(signature).unwrap();
+            let args = serde_json::from_str(args).unwrap();
+            try_structure_from_json(args, &signature)
+                .unwrap_err()
+                .to_string()
+        }
+
+        assert_eq!(
+            error("su", r#"["a"]"#),
+            "The signature 'su' expects 2 arguments, but 1 were given"
+        );
+        assert_eq!(
+            error("sy", r#"["a", 300]"#),
+            "Argument 1: Expected a value of type 'y', but got 300"
+        );
+        assert_eq!(
+            error("a{us}", r#"[{"a": "b"}]"#),
+            r#"Argument 0: Expected a value of type 'u', but got "a""#
+        );
+        assert_eq!(
+            error("o", r#"["not a path"]"#),
+            "Argument 0: Expected a value of type 'o', but got \"not a path\""
+        );
+    }
+
+    #[test]
+    fn variant_from_json() {
+        fn inferred(json: &str) -> String {
+            let json = serde_json::from_str(json).unwrap();
+            try_value_from_json(json, &zvariant::Signature::Variant)
+                .unwrap()
+                .to_string()
+        }
+
+        assert_eq!(inferred("1"), "<1>");
+        assert_eq!(inferred("5000000000"), "<int64 5000000000>");
+        assert_eq!(inferred("1.5"), "<1.5>");
+        assert_eq!(inferred(r#""s""#), r#"<"s">"#);
+        assert_eq!(inferred(r#"["a", "b"]"#), r#"<["a", "b"]>"#);
+        assert_eq!(inferred(r#"["a", 1]"#), r#"<[<"a">, <1>]>"#);
+        assert_eq!(inferred(r#"{"type": "u32", "value": 1}"#), "<uint32 1>");
+    }
 }
@@ -1,43 +1,94 @@
 use crate::error::{Error, RequestError};
-use crate::message::{InputMessage, OutputMessage, OwnedSignalKey, RequestId};
-use crate::state::WebSocketState;
+use crate::message::{
+    InputMessage, MethodCall, OutputMessage, OwnedInterfaceKey, RequestId, SubscriptionId,
+};
+use crate::pool::ConnectionPool;
+use crate::state::{
+    BusState, StreamKey, Subscription, SubscriptionInfo, SubscriptionKey, SubscriptionStream,
+    WebSocketState,
+};
 use crate::value::Value;
-use crate::{value, WebSocketEventHandler};
-use crate::{RequestResult, Result};
+use crate::{
+    export, monitor, names, object_manager, properties, signals, value, DBusConnectionTarget,
+    WebSocketEventHandler, WebSocketParameters,
+};
+use crate::{RequestResult, Result, ServerConfig};
 use axum::extract::ws::Message;
+use std::future::Future;
+use std::io;
 use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::StreamExt;
 use tracing::{error, info, instrument, trace, warn};
-use zbus::names::{BusName, InterfaceName, MemberName};
-use zbus::Proxy;
-use zvariant::ObjectPath;
-
-#[derive(Debug)]
-pub struct WebSocketMessageHandler<'a> {
-    dbus_connection: &'a zbus::Connection,
-    state: &'a WebSocketState,
+use zbus::message::Type;
+use zbus::names::{OwnedBusName, OwnedInterfaceName, OwnedMemberName, OwnedPropertyName};
+use zvariant::OwnedObjectPath;
+
+#[derive(Debug, Clone)]
+pub struct WebSocketMessageHandler {
+    state: Arc<WebSocketState>,
+    pool: Arc<ConnectionPool>,
+    config: ServerConfig,
+    params: WebSocketParameters,
+    /// Connection of the handled message
+    connection: DBusConnectionTarget,
 }
 
-impl<'a> WebSocketMessageHandler<'a> {
-    pub fn new(dbus_connection: &'a zbus::Connection, state: &'a WebSocketState) -> Self {
+impl WebSocketMessageHandler {
+    pub fn new(
+        state: Arc<WebSocketState>,
+        pool: Arc<ConnectionPool>,
+        config: ServerConfig,
+        params: WebSocketParameters,
+    ) -> Self {
         Self {
-            dbus_connection,
+            connection: params.target(),
             state,
+            pool,
+            config,
+            params,
         }
     }
 
     #[instrument]
-    async fn handle_message(&self, msg: Message) -> RequestResult<Option<OutputMessage>> {
+    async fn handle_message(&self, msg: Message) -> RequestResult<ControlFlow<()>> {
         trace!("WebSocket message received: {:?}", msg);
         match msg {
             Message::Text(json) => {
                 let input_message: InputMessage =
                     serde_json::from_str(&json).map_err(|err| RequestError::new(None, err))?;
                 trace!("Input message received: {:?}", input_message);
+                if let InputMessage::CancelRequest { request_id } = input_message {
+                    self.cancel_request(request_id);
+                    return Ok(ControlFlow::Continue(()));
+                }
                 let request_id = input_message.request_id();
-                return self
-                    .handle_input_message(input_message)
-                    .await
-                    .map_err(|err| RequestError::new(request_id, err));
+                if self.state.requests().len() >= self.config.max_pending_requests {
+                    return Err(RequestError::new(
+                        request_id,
+                        Error::TooManyRequests(self.config.max_pending_requests),
+                    ));
+                }
+                let connection = input_message.connection().unwrap_or(self.connection);
+                let handler = Self {
+                    connection,
+                    ..self.clone()
+                };
+                let ordered = input_message.is_ordered();
+                let task = async move {
+                    handler
+                        .handle_input_message(input_message)
+                        .await
+                        .map_err(|err| RequestError::new(request_id, err))
+                };
+                if ordered {
+                    self.state
+                        .requests()
+                        .spawn_ordered(request_id, connection, task);
+                } else {
+                    self.state.requests().spawn(request_id, connection, task);
+                }
             }
             Message::Binary(_) => {
                 return Err(
@@ -52,97 +103,604 @@ impl<'a> WebSocketMessageHandler<'a> {
                 } else {
                     warn!("WebSocket connection closed: {:?}", cf);
                 }
+                return Ok(ControlFlow::Break(()));
+            }
+            Message::Close(None) => {
+                info!("WebSocket connection closed");
+                return Ok(ControlFlow::Break(()));
             }
-            Message::Close(None) => info!("WebSocket connection closed"),
         }
-        Ok(None)
+        Ok(ControlFlow::Continue(()))
     }
 
     #[instrument]
     async fn handle_input_message(
         &self,
-        input_message: InputMessage<'a>,
+        input_message: InputMessage,
     ) -> Result<Option<OutputMessage>> {
         match input_message {
-            InputMessage::CallMethod {
+            InputMessage::CallMethod(method_call) => {
+                let timeout = method_call
+                    .timeout_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(self.config.method_timeout);
+                tokio::time::timeout(timeout, self.call_method(method_call))
+                    .await
+                    .map_err(|_| Error::Timeout(timeout))?
+            }
+            InputMessage::SubscribeSignal {
+                request_id,
+                key,
+                include_header,
+                delivery,
+                ..
+            } => {
+                let subscription = Subscription::new(
+                    self.key(SubscriptionKey::Signal {
+                        key: key.clone(),
+                        delivery: delivery.clone(),
+                    }),
+                    include_header,
+                );
+                let stream = async {
+                    let bus = self.bus().await?;
+                    signals::subscribe(bus.signals(), bus.connection(), key, delivery).await
+                };
+                self.subscribe(request_id, subscription, stream).await
+            }
+            InputMessage::UnsubscribeSignal {
+                request_id,
+                subscription_id,
+                ..
+            } => {
+                self.unsubscribe(subscription_id, "signal", |key| {
+                    matches!(key, SubscriptionKey::Signal { .. })
+                })?;
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::SubscribeMatchRule {
+                request_id,
+                rule,
+                include_header,
+                delivery,
+                ..
+            } => {
+                let subscription = Subscription::new(
+                    self.key(SubscriptionKey::MatchRule {
+                        rule: rule.clone(),
+                        delivery: delivery.clone(),
+                    }),
+                    include_header,
+                );
+                let stream = async {
+                    let bus = self.bus().await?;
+                    signals::subscribe_match_rule(bus.signals(), bus.connection(), rule, delivery)
+                        .await
+                };
+                self.subscribe(request_id, subscription, stream).await
+            }
+            InputMessage::UnsubscribeMatchRule {
+                request_id,
+                rule,
+                subscription_id,
+                ..
+            } => {
+                match (rule, subscription_id) {
+                    (Some(rule), None) => {
+                        let keys = self.state.subscriptions().remove_keys(|key| {
+                            key.connection == self.connection
+                                && matches!(&key.key, SubscriptionKey::MatchRule { rule: key_rule, .. } if *key_rule == rule)
+                        });
+                        for key in keys {
+                            self.state.signals().remove(&key);
+                        }
+                    }
+                    (None, Some(subscription_id)) => {
+                        self.unsubscribe(subscription_id, "match rule", |key| {
+                            matches!(key, SubscriptionKey::MatchRule { .. })
+                        })?
+                    }
+                    _ => {
+                        return Err(Error::UnsupportedFormat(
+                            "Either match rule or subscription id must be set".into(),
+                        ))
+                    }
+                }
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::Introspect {
                 request_id,
                 destination,
                 path,
+                ..
+            } => {
+                let bus = self.bus().await?;
+                let object = bus
+                    .introspection()
+                    .describe(bus.connection(), destination.as_ref(), &path)
+                    .await?;
+                Ok(Some(OutputMessage::Introspection { request_id, object }))
+            }
+            InputMessage::GetProperty {
+                request_id,
+                key,
+                name,
+                ..
+            } => {
+                let bus = self.bus().await?;
+                let value = properties::get(bus.connection(), &key, &name).await?;
+                Ok(Some(OutputMessage::Property { request_id, value }))
+            }
+            InputMessage::SetProperty {
+                request_id,
+                key,
+                name,
+                value,
+                ..
+            } => self.set_property(request_id, key, name, value).await,
+            InputMessage::GetAllProperties {
+                request_id, key, ..
+            } => {
+                let bus = self.bus().await?;
+                let properties = properties::get_all(bus.connection(), &key).await?;
+                Ok(Some(OutputMessage::Properties {
+                    request_id,
+                    properties,
+                }))
+            }
+            InputMessage::SubscribePropertyChanges {
+                request_id, key, ..
+            } => {
+                let bus = self.bus().await?;
+                let stream = properties::changes(bus.connection(), key.clone()).await?;
+                self.replace(SubscriptionKey::PropertyChanges(key), stream);
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::UnsubscribePropertyChanges {
+                request_id, key, ..
+            } => {
+                self.remove(SubscriptionKey::PropertyChanges(key));
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::SubscribeObjectManager {
+                request_id,
+                key,
+                properties_changed,
+                ..
+            } => {
+                let bus = self.bus().await?;
+                let stream = object_manager::changes(
+                    bus.connection(),
+                    request_id,
+                    key.clone(),
+                    properties_changed,
+                )
+                .await?;
+                // The managed objects snapshot is the first item of the stream
+                self.replace(SubscriptionKey::ObjectManager(key), stream);
+                Ok(None)
+            }
+            InputMessage::UnsubscribeObjectManager {
+                request_id, key, ..
+            } => {
+                self.remove(SubscriptionKey::ObjectManager(key));
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::ListSubscriptions { request_id, .. } => {
+                let subscriptions = self
+                    .state
+                    .subscriptions()
+                    .list()
+                    .into_iter()
+                    .map(|(subscription_id, subscription)| SubscriptionInfo {
+                        subscription_id,
+                        subscription,
+                    })
+                    .collect();
+                Ok(Some(OutputMessage::Subscriptions {
+                    request_id,
+                    subscriptions,
+                }))
+            }
+            InputMessage::Monitor {
+                request_id, rules, ..
+            } => {
+                let subscription = Subscription::new(
+                    self.key(SubscriptionKey::Monitor {
+                        rules: rules.clone(),
+                    }),
+                    true,
+                );
+                let params = self.params.for_target(self.connection)?;
+                let stream = monitor::monitor(&params, &rules);
+                self.subscribe(request_id, subscription, stream).await
+            }
+            InputMessage::StopMonitor {
+                request_id,
+                subscription_id,
+                ..
+            } => {
+                self.unsubscribe(subscription_id, "monitor", |key| {
+                    matches!(key, SubscriptionKey::Monitor { .. })
+                })?;
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::ExportObject {
+                request_id,
+                path,
+                interfaces,
+                properties,
+                ..
+            } => {
+                self.require_own_connection("Object export")?;
+                let bus = self.bus().await?;
+                let exported = bus.exported();
+                exported.export(path, interfaces, properties)?;
+                if let Some(calls) = export::serve(exported, bus.connection()).await? {
+                    self.state
+                        .signals()
+                        .insert(self.key(SubscriptionKey::ExportedObjects), calls);
+                }
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::UnexportObject {
+                request_id, path, ..
+            } => {
+                let bus = self.bus().await?;
+                if !bus.exported().unexport(bus.connection(), &path).await {
+                    return Err(export::Error::ObjectNotExported(path).into());
+                }
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::SetExportedProperty {
+                request_id,
+                path,
                 interface,
-                method_name,
-                args: body,
+                name,
+                value,
+                ..
             } => {
-                self.call_method(request_id, destination, path, interface, method_name, body)
-                    .await
+                let bus = self.bus().await?;
+                let value = bus
+                    .exported()
+                    .set_property(&path, &interface, &name, value)?;
+                export::properties_changed(bus.connection(), &path, &interface, &name, &value)
+                    .await?;
+                Ok(Some(OutputMessage::Success { request_id }))
             }
-            InputMessage::SubscribeSignal { request_id, key } => {
-                self.subscribe_signal(request_id, key).await
+            InputMessage::ReturnMethodCall {
+                request_id,
+                call_id,
+                args,
+                ..
+            } => {
+                let bus = self.bus().await?;
+                bus.exported()
+                    .return_call(bus.connection(), call_id, args)
+                    .await?;
+                Ok(Some(OutputMessage::Success { request_id }))
+            }
+            InputMessage::ReturnMethodError {
+                request_id,
+                call_id,
+                error_name,
+                message,
+                ..
+            } => {
+                let bus = self.bus().await?;
+                bus.exported()
+                    .return_error(bus.connection(), call_id, error_name, message)
+                    .await?;
+                Ok(Some(OutputMessage::Success { request_id }))
             }
-            InputMessage::UnsubscribeSignal { request_id, key } => {
-                self.state.signals().remove(&key).await;
+            InputMessage::EmitSignal {
+                request_id,
+                destination,
+                path,
+                interface,
+                name,
+                args,
+                ..
+            } => {
+                self.emit_signal(destination, path, interface, name, args)
+                    .await?;
                 Ok(Some(OutputMessage::Success { request_id }))
             }
+            InputMessage::RequestName {
+                request_id,
+                name,
+                flags,
+                ..
+            } => {
+                self.require_own_connection("Name ownership")?;
+                let bus = self.bus().await?;
+                let names = bus.names();
+                if let Some(changes) = names::watch(names, bus.connection()).await? {
+                    self.state
+                        .signals()
+                        .insert(self.key(SubscriptionKey::OwnedNames), changes);
+                }
+                let reply = names
+                    .request(bus.connection(), name.clone(), &flags)
+                    .await?;
+                Ok(Some(OutputMessage::NameRequested {
+                    request_id,
+                    name,
+                    reply,
+                }))
+            }
+            InputMessage::ReleaseName {
+                request_id, name, ..
+            } => {
+                self.require_own_connection("Name ownership")?;
+                let bus = self.bus().await?;
+                let reply = bus.names().release(bus.connection(), name.clone()).await?;
+                Ok(Some(OutputMessage::NameReleased {
+                    request_id,
+                    name,
+                    reply,
+                }))
+            }
+            // The cancel requests are handled before the requests are spawned
+            InputMessage::CancelRequest { .. } => {
+                unreachable!("Cancel request is not spawned")
+            }
+        }
+    }
+
+    // The connection of the handled message is opened on the first use
+    async fn bus(&self) -> Result<Arc<BusState>> {
+        let params = self.params.for_target(self.connection)?;
+        self.state.bus(params, &self.pool).await
+    }
+
+    fn key(&self, key: SubscriptionKey) -> StreamKey {
+        StreamKey::new(self.connection, key)
+    }
+
+    // The exported objects and the names belong to the connection, so they cannot be shared
+    fn require_own_connection(&self, feature: &'static str) -> Result<()> {
+        if self.params.shared {
+            return Err(Error::SharedConnection(feature));
+        }
+        Ok(())
+    }
+
+    // The cancelled request replies with the error, the request can be already completed
+    fn cancel_request(&self, request_id: RequestId) {
+        if !self.state.requests().cancel(request_id) {
+            trace!("Request {} is not pending", request_id);
         }
     }
 
     #[instrument]
     async fn call_method(
         &self,
-        request_id: Option<RequestId>,
-        destination: Option<BusName<'a>>,
-        path: ObjectPath<'a>,
-        interface: Option<InterfaceName<'a>>,
-        method_name: MemberName<'a>,
-        body: Vec<Value>,
+        MethodCall {
+            request_id,
+            destination,
+            path,
+            mut interface,
+            method_name,
+            signature,
+            args,
+            flags,
+            include_header,
+            ..
+        }: MethodCall,
     ) -> Result<Option<OutputMessage>> {
-        let response = if body.is_empty() {
-            self.dbus_connection
-                .call_method(destination, path, interface, method_name, &())
-                .await
-        } else {
-            let body = value::try_structure_from_fields(body)?;
+        let bus = self.bus().await?;
+        let body = match signature {
+            Some(signature) => value::try_structure_from_json(args, &signature)?,
+            None if args.is_empty() => None,
+            None => match typed_values(&args) {
+                Some(args) => Some(value::try_structure_from_fields(args)?),
+                None => {
+                    let (method_interface, signature) = bus
+                        .introspection()
+                        .method_signature(
+                            bus.connection(),
+                            destination.as_ref(),
+                            &path,
+                            interface.as_ref(),
+                            &method_name,
+                        )
+                        .await?;
+                    trace!("Introspected method signature: {}", signature);
+                    interface = Some(method_interface);
+                    value::try_structure_from_json(args, &signature)?
+                }
+            },
+        };
+        let mut builder = zbus::Message::method_call(path, method_name)?;
+        if let Some(sender) = bus.connection().unique_name() {
+            builder = builder.sender(sender)?;
+        }
+        if let Some(destination) = destination {
+            builder = builder.destination(destination)?;
+        }
+        if let Some(interface) = interface {
+            builder = builder.interface(interface)?;
+        }
+        for flag in flags.header_flags() {
+            builder = builder.with_flags(flag)?;
+        }
+        let msg = if let Some(body) = body {
             trace!("Message body: ({}){:?}", body.signature(), body);
-            self.dbus_connection
-                .call_method(destination, path, interface, method_name, &body)
-                .await
+            builder.build(&body)?
+        } else {
+            builder.build(&())?
+        };
+        if flags.no_reply_expected {
+            bus.connection().send(&msg).await?;
+            return Ok(Some(OutputMessage::Success { request_id }));
         }
-        .map_err(|err| RequestError::new(request_id, err))?;
+        let response = call(bus.connection(), &msg)
+            .await
+            .map_err(|err| RequestError::new(request_id, err))?;
         Ok(Some(OutputMessage::from_method_call_result(
-            response, request_id,
+            response,
+            request_id,
+            include_header,
         )?))
     }
 
     #[instrument]
-    async fn subscribe_signal(
+    async fn set_property(
         &self,
         request_id: Option<RequestId>,
-        OwnedSignalKey { member, args }: OwnedSignalKey,
+        key: OwnedInterfaceKey,
+        name: OwnedPropertyName,
+        value: serde_json::Value,
     ) -> Result<Option<OutputMessage>> {
-        let proxy = Proxy::new(
-            self.dbus_connection,
-            member.destination.clone(),
-            member.path.clone(),
-            member.interface.clone(),
-        )
-        .await?;
-        let stream = if args.is_empty() {
-            proxy.receive_signal(member.name.clone()).await
+        let bus = self.bus().await?;
+        let value = match serde_json::from_value::<Value>(value.clone()) {
+            Ok(value) => value.try_into()?,
+            Err(_) => {
+                let signature = bus
+                    .introspection()
+                    .property_signature(
+                        bus.connection(),
+                        key.destination.as_ref(),
+                        &key.path,
+                        &key.interface,
+                        &name,
+                    )
+                    .await?;
+                trace!("Introspected property type: {}", signature);
+                value::try_value_from_json(value, &signature)?
+            }
+        };
+        properties::set(bus.connection(), &key, &name, value).await?;
+        Ok(Some(OutputMessage::Success { request_id }))
+    }
+
+    #[instrument]
+    async fn emit_signal(
+        &self,
+        destination: Option<OwnedBusName>,
+        path: OwnedObjectPath,
+        interface: OwnedInterfaceName,
+        name: OwnedMemberName,
+        args: Vec<Value>,
+    ) -> Result<()> {
+        let bus = self.bus().await?;
+        if args.is_empty() {
+            bus.connection()
+                .emit_signal(destination, path, interface, name, &())
+                .await?;
         } else {
-            let args: Vec<(u8, &str)> = args.iter().map(|(i, s)| (*i, s.as_str())).collect();
-            proxy
-                .receive_signal_with_args(member.name.clone(), &args)
-                .await
+            let body = value::try_structure_from_fields(args)?;
+            trace!("Signal body: ({}){:?}", body.signature(), body);
+            bus.connection()
+                .emit_signal(destination, path, interface, name, &body)
+                .await?;
         }
-        .map_err(|err| RequestError::new(request_id, err))?;
+        Ok(())
+    }
+
+    // The stream is created only for the first subscription of the key,
+    // the subscription is added after its stream is created, so it is never listed without the stream
+    async fn subscribe(
+        &self,
+        request_id: Option<RequestId>,
+        subscription: Subscription,
+        stream: impl Future<Output = Result<SubscriptionStream>>,
+    ) -> Result<Option<OutputMessage>> {
+        let subscriptions = self.state.subscriptions();
+        let subscription_id = match subscriptions.add_to_existing(&subscription) {
+            Some(subscription_id) => subscription_id,
+            None => {
+                let key = subscription.key.clone();
+                let stream = stream.await?;
+                // The concurrent subscription of the same key could create the stream first
+                let (subscription_id, first) = subscriptions.add(subscription);
+                if first {
+                    self.state.signals().insert(key, stream);
+                }
+                subscription_id
+            }
+        };
+        Ok(Some(OutputMessage::Subscription {
+            request_id,
+            subscription_id,
+        }))
+    }
+
+    // The subscription must be of the kind the request unsubscribes from
+    fn unsubscribe(
+        &self,
+        subscription_id: SubscriptionId,
+        kind: &str,
+        accepts: impl Fn(&SubscriptionKey) -> bool,
+    ) -> Result<()> {
+        let subscriptions = self.state.subscriptions();
+        if subscriptions
+            .get(subscription_id)
+            .is_some_and(|subscription| !accepts(&subscription.key.key))
+        {
+            return Err(Error::UnsupportedFormat(format!(
+                "Subscription {} is not a {} subscription",
+                subscription_id, kind
+            )));
+        }
+        if let Some(key) = subscriptions.remove(subscription_id) {
+            self.state.signals().remove(&key);
+        }
+        Ok(())
+    }
+
+    // The key has the single subscription, the stream of the repeated subscription replaces the previous one
+    fn replace(&self, key: SubscriptionKey, stream: SubscriptionStream) {
+        let key = self.key(key);
         self.state
-            .signals()
-            .insert(OwnedSignalKey { member, args }, stream)
-            .await;
-        Ok(Some(OutputMessage::Success { request_id }))
+            .subscriptions()
+            .remove_keys(|other| *other == key);
+        self.state
+            .subscriptions()
+            .add(Subscription::new(key.clone(), false));
+        self.state.signals().insert(key, stream);
+    }
+
+    fn remove(&self, key: SubscriptionKey) {
+        let key = self.key(key);
+        self.state
+            .subscriptions()
+            .remove_keys(|other| *other == key);
+        self.state.signals().remove(&key);
     }
 }
 
-impl<'a> WebSocketEventHandler<'a, Result<Option<Message>>> for WebSocketMessageHandler<'a> {
+// Same as `zbus::Connection::call_method`, but the message is built by the caller to set the header flags
+async fn call(connection: &zbus::Connection, msg: &zbus::Message) -> zbus::Result<zbus::Message> {
+    let serial = msg.primary_header().serial_num();
+    let mut replies = zbus::MessageStream::from(connection);
+    connection.send(msg).await?;
+    while let Some(reply) = replies.next().await {
+        let reply = reply?;
+        if reply.header().reply_serial() != Some(serial) {
+            continue;
+        }
+        match reply.message_type() {
+            Type::MethodReturn => return Ok(reply),
+            Type::Error => return Err(reply.into()),
+            _ => {}
+        }
+    }
+    Err(zbus::Error::InputOutput(Arc::new(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "socket closed",
+    ))))
+}
+
+// Arguments without explicit types are typed using the introspection data
+fn typed_values(args: &[serde_json::Value]) -> Option<Vec<Value>> {
+    args.iter()
+        .map(|arg| serde_json::from_value(arg.clone()).ok())
+        .collect()
+}
+
+impl WebSocketEventHandler<'static, Result<Option<Message>>> for WebSocketMessageHandler {
     #[instrument]
     async fn handle(
         &self,
@@ -150,15 +708,33 @@ impl<'a> WebSocketEventHandler<'a, Result<Option<Message>>> for WebSocketMessage
     ) -> ControlFlow<Option<OutputMessage>, Option<OutputMessage>> {
         match event {
             Ok(Some(msg)) => match self.handle_message(msg).await {
-                Ok(Some(output_message)) => ControlFlow::Continue(Some(output_message)),
-                Ok(None) => ControlFlow::Break(None),
+                Ok(ControlFlow::Continue(())) => ControlFlow::Continue(None),
+                Ok(ControlFlow::Break(())) => ControlFlow::Break(None),
                 Err(err) => {
                     error!("Message handle error: {}", err);
                     ControlFlow::Continue(Some(err.into()))
                 }
             },
-            Ok(None) => ControlFlow::Continue(None),
+            Ok(None) => ControlFlow::Break(None),
             Err(err) => ControlFlow::Break(Some(err.into())),
         }
     }
 }
+
+impl WebSocketEventHandler<'static, RequestResult<Option<OutputMessage>>>
+    for WebSocketMessageHandler
+{
+    #[instrument]
+    async fn handle(
+        &self,
+        event: RequestResult<Option<OutputMessage>>,
+    ) -> ControlFlow<Option<OutputMessage>, Option<OutputMessage>> {
+        match event {
+            Ok(output_message) => ControlFlow::Continue(output_message),
+            Err(err) => {
+                error!("Request handle error: {}", err);
+                ControlFlow::Continue(Some(err.into()))
+            }
+        }
+    }
+}